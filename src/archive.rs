@@ -0,0 +1,143 @@
+// © Zach Nielsen 2024
+
+use crate::config::{ArchiveConfig, ArchiveMode};
+use crate::data::write_atomic;
+
+use anyhow::{Context, Result};
+use chrono::{Datelike, NaiveDate};
+
+use std::path::{Path, PathBuf};
+
+/// Where a scanned receipt's photos end up
+pub struct ArchivedReceipt<'a> {
+    pub date: NaiveDate,
+    /// Store key, e.g. `costco`
+    pub store: &'a str,
+    pub id: &'a str,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+impl ArchivedReceipt<'_> {
+    /// `<dir>/YYYY/MM/<store>/YYYY-MM-DD-<store>-<id>.<ext>`, numbered like the photos of a long
+    /// receipt when there is more than one part
+    pub fn path(&self, dir: &Path, original: &Path, part: Option<usize>) -> PathBuf {
+        let ext = original.extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let mut name = format!("{}-{}-{}", self.date.format("%Y-%m-%d"), self.store, self.id);
+        if let Some(n) = part {
+            name += &format!("-{}", n);
+        }
+        if !ext.is_empty() {
+            name += &format!(".{}", ext);
+        }
+        dir.join(format!("{:04}", self.date.year()))
+            .join(format!("{:02}", self.date.month()))
+            .join(self.store)
+            .join(name)
+    }
+}
+
+/// Move or copy a receipt's photos into the archive. Moved photos are renamed in the done file too,
+/// so they are still known to be done.
+pub fn archive(parts: &[String], receipt: &ArchivedReceipt, config: &ArchiveConfig, done_file: &Path) -> Result<Vec<PathBuf>> {
+    let Some(dir) = &config.dir else {
+        return Ok(Vec::new());
+    };
+
+    let mut archived = Vec::new();
+    for (i, part) in parts.iter().enumerate() {
+        let src = Path::new(part);
+        let dest = receipt.path(dir, src, (parts.len() > 1).then_some(i + 1));
+        // Already archived, e.g. when rescanning an archive inside the image directory
+        if dest == src {
+            continue;
+        }
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create archive directory: {}", parent.display()))?;
+        }
+
+        match config.mode {
+            ArchiveMode::Copy => {
+                std::fs::copy(src, &dest)
+                    .with_context(|| format!("Failed to copy {} to {}", src.display(), dest.display()))?;
+            }
+            ArchiveMode::Move => {
+                // Rename fails across filesystems
+                if std::fs::rename(src, &dest).is_err() {
+                    std::fs::copy(src, &dest)
+                        .with_context(|| format!("Failed to move {} to {}", src.display(), dest.display()))?;
+                    std::fs::remove_file(src)
+                        .with_context(|| format!("Failed to remove {} after archiving", src.display()))?;
+                }
+                rename_done(done_file, part, &dest.to_string_lossy())?;
+            }
+        }
+        archived.push(dest);
+    }
+    Ok(archived)
+}
+
+fn rename_done(done_file: &Path, old: &str, new: &str) -> Result<()> {
+    let content = std::fs::read_to_string(done_file)
+        .with_context(|| format!("Failed to read done file: {}", done_file.display()))?;
+    let mut lines: Vec<&str> = content.lines().map(|l| if l == old { new } else { l }).collect();
+    if !lines.contains(&new) {
+        lines.push(new);
+    }
+    write_atomic(done_file, &(lines.join("\n") + "\n"))
+        .with_context(|| format!("Failed to write done file: {}", done_file.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receipt() -> ArchivedReceipt<'static> {
+        ArchivedReceipt { date: NaiveDate::from_ymd_opt(2024, 7, 21).unwrap(), store: "costco", id: "0a1b2c3d4e5f" }
+    }
+
+    #[test]
+    fn test_archive_path() {
+        let path = receipt().path(Path::new("/a"), Path::new("/i/IMG_1234.JPG"), None);
+        assert_eq!(path, PathBuf::from("/a/2024/07/costco/2024-07-21-costco-0a1b2c3d4e5f.jpg"));
+        let path = receipt().path(Path::new("/a"), Path::new("/i/2024-07-21-costco-2.png"), Some(2));
+        assert_eq!(path, PathBuf::from("/a/2024/07/costco/2024-07-21-costco-0a1b2c3d4e5f-2.png"));
+    }
+
+    #[test]
+    fn test_archive_move_updates_done_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("2024-07-21-costco.jpg");
+        let done_file = dir.path().join("done");
+        std::fs::write(&src, b"jpg").unwrap();
+        std::fs::write(&done_file, format!("/other.jpg\n{}\n", src.display())).unwrap();
+        let config = ArchiveConfig { dir: Some(dir.path().join("archive")), mode: ArchiveMode::Move };
+
+        let archived = archive(&[src.to_string_lossy().into_owned()], &receipt(), &config, &done_file).unwrap();
+
+        assert_eq!(archived.len(), 1);
+        assert!(!src.exists());
+        assert_eq!(std::fs::read(&archived[0]).unwrap(), b"jpg");
+        let done = std::fs::read_to_string(&done_file).unwrap();
+        assert_eq!(done, format!("/other.jpg\n{}\n", archived[0].display()));
+    }
+
+    #[test]
+    fn test_archive_copy_keeps_original() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("2024-07-21-costco.jpg");
+        let done_file = dir.path().join("done");
+        std::fs::write(&src, b"jpg").unwrap();
+        std::fs::write(&done_file, format!("{}\n", src.display())).unwrap();
+        let config = ArchiveConfig { dir: Some(dir.path().join("archive")), mode: ArchiveMode::Copy };
+
+        let archived = archive(&[src.to_string_lossy().into_owned()], &receipt(), &config, &done_file).unwrap();
+
+        assert!(src.exists());
+        assert!(archived[0].exists());
+        assert_eq!(std::fs::read_to_string(&done_file).unwrap(), format!("{}\n", src.display()));
+    }
+}
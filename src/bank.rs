@@ -0,0 +1,251 @@
+// © Zach Nielsen 2024
+
+use crate::config::BankConfig;
+use crate::data::Purchases;
+use crate::export::transactions;
+use crate::output::round_cents;
+
+use anyhow::{Context, Result, bail};
+use chrono::NaiveDate;
+
+use std::path::Path;
+
+/// A grocery charge from a bank or credit card statement
+#[derive(Clone, Debug)]
+pub struct Charge {
+    pub date: NaiveDate,
+    pub store: String,
+    pub description: String,
+    pub amount: f64,
+}
+
+/// Receipt-level total, summed from the purchases scanned off it
+#[derive(Clone, Debug)]
+pub struct ReceiptTotal {
+    pub id: String,
+    pub date: NaiveDate,
+    pub store: String,
+    pub total: f64,
+}
+
+pub struct Matches {
+    pub matched: Vec<(Charge, ReceiptTotal)>,
+    /// Charges with no receipt; the photo is probably missing
+    pub unmatched_charges: Vec<Charge>,
+    /// Receipts with no charge; the total was probably misparsed
+    pub unmatched_receipts: Vec<ReceiptTotal>,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+fn parse_amount(s: &str) -> Option<f64> {
+    let cleaned: String = s.chars().filter(|c| !matches!(c, '$' | ',' | ' ')).collect();
+    // Some banks write debits in parentheses
+    if let Some(inner) = cleaned.strip_prefix('(').and_then(|c| c.strip_suffix(')')) {
+        return inner.parse::<f64>().ok().map(|v| -v);
+    }
+    cleaned.parse().ok()
+}
+
+/// Read grocery charges from a statement CSV, keeping only rows whose description names a known store
+pub fn read_statement(path: &Path, config: &BankConfig) -> Result<Vec<Charge>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_path(path)
+        .with_context(|| format!("Failed to open statement: {}", path.display()))?;
+    let headers = reader.headers()
+        .with_context(|| format!("Failed to read statement header: {}", path.display()))?
+        .clone();
+    let column = |name: &str| -> Result<usize> {
+        match headers.iter().position(|h| h.trim().eq_ignore_ascii_case(name)) {
+            Some(i) => Ok(i),
+            None => bail!("Statement has no '{}' column (found: {:?})", name, headers.iter().collect::<Vec<_>>()),
+        }
+    };
+    let date_col = column(&config.date_column)?;
+    let desc_col = column(&config.description_column)?;
+    let amount_col = column(&config.amount_column)?;
+
+    let mut charges = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record.with_context(|| format!("Failed to read statement row {}", i + 2))?;
+        let (Some(date), Some(desc), Some(amount)) = (record.get(date_col), record.get(desc_col), record.get(amount_col)) else {
+            log::warn!("skipping short statement row {}: {:?}", i + 2, record);
+            continue;
+        };
+
+        let upper = desc.to_uppercase();
+        let Some(store) = config.stores.iter()
+            .find(|(_, patterns)| patterns.iter().any(|p| upper.contains(&p.to_uppercase())))
+            .map(|(store, _)| store.clone()) else {
+            continue;
+        };
+        let date = match NaiveDate::parse_from_str(date.trim(), &config.date_format) {
+            Ok(d) => d,
+            Err(_) => {
+                log::warn!("skipping statement row {} with bad date '{}'", i + 2, date);
+                continue;
+            }
+        };
+        let Some(mut amount) = parse_amount(amount) else {
+            log::warn!("skipping statement row {} with bad amount '{}'", i + 2, amount);
+            continue;
+        };
+        if config.negate_amounts {
+            amount = -amount;
+        }
+        // Refunds and payments
+        if amount <= 0.0 {
+            continue;
+        }
+
+        charges.push(Charge { date, store, description: desc.trim().to_owned(), amount });
+    }
+
+    Ok(charges)
+}
+
+pub fn receipt_totals(purchases: &Purchases) -> Vec<ReceiptTotal> {
+    transactions(purchases).into_iter()
+        .filter(|tx| tx.purchases.iter().any(|p| p.receipt.is_some()))
+        .map(|tx| ReceiptTotal {
+            total: round_cents(tx.purchases.iter().map(|p| p.price).sum()),
+            id: tx.id,
+            date: tx.date,
+            store: tx.payee,
+        })
+        .collect()
+}
+
+/// Pair charges with receipts from the same store, within the date window and amount tolerance.
+///
+/// Closest pairs are taken first so one receipt never matches two charges. Only receipts inside
+/// the statement's date range are reported as unmatched.
+pub fn match_charges(charges: Vec<Charge>, receipts: Vec<ReceiptTotal>, config: &BankConfig) -> Matches {
+    let window = chrono::Duration::days(config.date_window_days);
+    let tolerance = |amount: f64| config.amount_tolerance.max(amount * config.amount_tolerance_pct / 100.0);
+
+    let mut candidates: Vec<(f64, i64, usize, usize)> = Vec::new();
+    for (ci, c) in charges.iter().enumerate() {
+        for (ri, r) in receipts.iter().enumerate() {
+            let days = (c.date - r.date).num_days().abs();
+            let diff = (c.amount - r.total).abs();
+            if r.store == c.store && days <= config.date_window_days && diff <= tolerance(c.amount) {
+                candidates.push((diff, days, ci, ri));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal).then(a.1.cmp(&b.1)));
+
+    let mut charge_used = vec![false; charges.len()];
+    let mut receipt_used = vec![false; receipts.len()];
+    let mut matched = Vec::new();
+    for (_, _, ci, ri) in candidates {
+        if charge_used[ci] || receipt_used[ri] { continue; }
+        charge_used[ci] = true;
+        receipt_used[ri] = true;
+        matched.push((charges[ci].clone(), receipts[ri].clone()));
+    }
+    matched.sort_by_key(|m| m.0.date);
+
+    let first = charges.iter().map(|c| c.date).min();
+    let last = charges.iter().map(|c| c.date).max();
+    let in_statement = |d: NaiveDate| match (first, last) {
+        (Some(first), Some(last)) => d >= first - window && d <= last + window,
+        _ => false,
+    };
+
+    let unmatched_charges = charges.into_iter().zip(charge_used)
+        .filter(|(_, used)| !used)
+        .map(|(c, _)| c)
+        .collect();
+    let unmatched_receipts = receipts.into_iter().zip(receipt_used)
+        .filter(|(r, used)| !used && in_statement(r.date))
+        .map(|(r, _)| r)
+        .collect();
+
+    Matches { matched, unmatched_charges, unmatched_receipts }
+}
+
+pub fn print_matches(m: &Matches) {
+    println!("Matched: {}", m.matched.len());
+    for (c, r) in &m.matched {
+        println!("  {} {:<12} {:>9.2}  receipt {} {} {:>9.2}  diff {:>6.2}",
+            c.date, c.store, c.amount, r.id, r.date, r.total, c.amount - r.total);
+    }
+    println!("\nCharges with no receipt (missing photo?): {}", m.unmatched_charges.len());
+    for c in &m.unmatched_charges {
+        println!("  {} {:<12} {:>9.2}  {}", c.date, c.store, c.amount, c.description);
+    }
+    println!("\nReceipts with no charge (misparsed total?): {}", m.unmatched_receipts.len());
+    for r in &m.unmatched_receipts {
+        println!("  {} {:<12} {:>9.2}  receipt {}", r.date, r.store, r.total, r.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    fn charge(d: &str, store: &str, amount: f64) -> Charge {
+        Charge { date: date(d), store: store.to_owned(), description: store.to_uppercase(), amount }
+    }
+
+    fn receipt(id: &str, d: &str, store: &str, total: f64) -> ReceiptTotal {
+        ReceiptTotal { id: id.to_owned(), date: date(d), store: store.to_owned(), total }
+    }
+
+    #[test]
+    fn test_parse_amount() {
+        assert_eq!(parse_amount("$1,234.50"), Some(1234.50));
+        assert_eq!(parse_amount("-12.00"), Some(-12.00));
+        assert_eq!(parse_amount("(12.00)"), Some(-12.00));
+        assert_eq!(parse_amount("abc"), None);
+    }
+
+    #[test]
+    fn test_read_statement_filters_stores() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("statement.csv");
+        std::fs::write(&path, "Date,Description,Amount\n\
+            07/21/2024,COSTCO WHSE #123,-84.12\n\
+            07/22/2024,\"SHELL OIL, INC\",-40.00\n\
+            07/23/2024,WINCO FOODS,-20.50\n\
+            07/24/2024,COSTCO REFUND,15.00\n").unwrap();
+
+        let config = BankConfig { negate_amounts: true, ..BankConfig::default() };
+        let charges = read_statement(&path, &config).unwrap();
+        assert_eq!(charges.len(), 2);
+        assert_eq!(charges[0].store, "Costco");
+        assert_eq!(charges[0].amount, 84.12);
+        assert_eq!(charges[0].date, date("2024-07-21"));
+        assert_eq!(charges[1].store, "WinCo");
+    }
+
+    #[test]
+    fn test_match_charges() {
+        let charges = vec![
+            charge("2024-07-21", "Costco", 84.12),
+            charge("2024-07-23", "WinCo", 20.50),
+            charge("2024-07-25", "Costco", 30.00),
+        ];
+        let receipts = vec![
+            receipt("aaa", "2024-07-20", "Costco", 80.00),
+            receipt("bbb", "2024-07-23", "WinCo", 2.05),
+            receipt("ccc", "2024-07-25", "Costco", 30.00),
+            receipt("old", "2023-01-01", "Costco", 10.00),
+        ];
+        let m = match_charges(charges, receipts, &BankConfig::default());
+
+        let pairs: Vec<(&str, f64)> = m.matched.iter().map(|(c, r)| (r.id.as_str(), c.amount)).collect();
+        assert_eq!(pairs, vec![("aaa", 84.12), ("ccc", 30.00)]);
+        assert_eq!(m.unmatched_charges.len(), 1);
+        assert_eq!(m.unmatched_charges[0].store, "WinCo");
+        assert_eq!(m.unmatched_receipts.len(), 1);
+        assert_eq!(m.unmatched_receipts[0].id, "bbb");
+    }
+}
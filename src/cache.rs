@@ -0,0 +1,92 @@
+// © Zach Nielsen 2024
+
+use crate::config::{OcrConfig, PreprocessConfig};
+use crate::ocr::OcrOutput;
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use std::path::PathBuf;
+
+/// OCR output saved by image contents and the settings used to read it, so receipts can be parsed
+/// again without redoing preprocessing and OCR
+pub struct OcrCache {
+    pub dir: PathBuf,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+impl OcrCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Changes whenever the image or any setting that affects the OCR text changes
+    pub fn key(image: &[u8], pre: &PreprocessConfig, ocr: &OcrConfig) -> Result<String> {
+        // Only used after OCR, when flagging purchases
+        let ocr = OcrConfig { min_confidence: 0.0, ..ocr.clone() };
+        let settings = serde_json::to_vec(&(pre, &ocr))
+            .context("Failed to serialize OCR settings")?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(image);
+        hasher.update(&settings);
+        Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    pub fn get(&self, key: &str) -> Option<OcrOutput> {
+        let path = self.path(key);
+        let text = std::fs::read_to_string(&path).ok()?;
+        match serde_json::from_str(&text) {
+            Ok(output) => Some(output),
+            Err(e) => {
+                log::warn!("ignoring unreadable OCR cache entry {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    pub fn put(&self, key: &str, output: &OcrOutput) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create OCR cache directory: {}", self.dir.display()))?;
+        let path = self.path(key);
+        let text = serde_json::to_string(output).context("Failed to serialize OCR output")?;
+        std::fs::write(&path, text)
+            .with_context(|| format!("Failed to write OCR cache entry: {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_follows_image_and_settings() {
+        let pre = PreprocessConfig::default();
+        let ocr = OcrConfig::default();
+        let key = OcrCache::key(b"image", &pre, &ocr).unwrap();
+
+        assert_eq!(key, OcrCache::key(b"image", &pre, &ocr).unwrap());
+        assert_ne!(key, OcrCache::key(b"other", &pre, &ocr).unwrap());
+        assert_ne!(key, OcrCache::key(b"image", &PreprocessConfig { scale: 2.0, ..pre.clone() }, &ocr).unwrap());
+        assert_ne!(key, OcrCache::key(b"image", &pre, &OcrConfig { psm: Some(4), ..ocr.clone() }).unwrap());
+        assert_eq!(key, OcrCache::key(b"image", &pre, &OcrConfig { min_confidence: 90.0, ..ocr }).unwrap());
+    }
+
+    #[test]
+    fn test_put_and_get() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = OcrCache::new(dir.path().join("ocr_cache"));
+        let output = OcrOutput::from_tsv(&crate::ocr::fixture_tsv(&[&[("WinCo", 95.0)]]));
+
+        assert!(cache.get("abc").is_none());
+        cache.put("abc", &output).unwrap();
+        let cached = cache.get("abc").unwrap();
+        assert_eq!(cached.text, "WinCo");
+        assert_eq!(cached.lines, output.lines);
+    }
+}
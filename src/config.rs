@@ -2,15 +2,345 @@
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
     pub image_dir: PathBuf,
     pub upscaled_image_dir: PathBuf,
     pub done_file: PathBuf,
     pub rules_file: PathBuf,
     pub purchases_file: PathBuf,
+    /// OCR text from earlier scans. Defaults to `ocr_cache` next to the done file.
+    #[serde(default)]
+    pub ocr_cache_dir: Option<PathBuf>,
+    /// How each receipt was read, one JSON file per receipt. Defaults to `receipt_reports` next to
+    /// the done file.
+    #[serde(default)]
+    pub receipt_report_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub images: ImagesConfig,
+    #[serde(default)]
+    pub export: ExportConfig,
+    #[serde(default)]
+    pub bank: BankConfig,
+    #[serde(default)]
+    pub preprocess: PreprocessConfig,
+    #[serde(default)]
+    pub ocr: OcrConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+    /// Per-store overrides, keyed by `costco`, `fredmeyer` or `winco`
+    #[serde(default)]
+    pub stores: HashMap<String, StoreConfig>,
+}
+
+/// Which files under `image_dir` are scanned
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImagesConfig {
+    /// Scan subdirectories too, e.g. `images/2024/07/`; off by default
+    pub recursive: bool,
+    /// Extensions of files to scan, case insensitive
+    pub extensions: Vec<String>,
+    /// Glob patterns, relative to `image_dir`. When any are given, only matching files are scanned.
+    pub include: Vec<String>,
+    /// Glob patterns, relative to `image_dir`, of files never to scan
+    pub exclude: Vec<String>,
+}
+
+/// Image processing done before OCR. Cropping and deskewing happen first, then scaling, then
+/// `steps` in the order listed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PreprocessConfig {
+    /// Crop to the paper's edges, dropping countertop and other background
+    pub crop: bool,
+    /// Rotate so text lines run horizontally. Perspective isn't corrected.
+    pub deskew: bool,
+    pub scale: f32,
+    pub steps: Vec<PreprocessStep>,
+    /// Half-width in pixels of the neighbourhood `threshold` compares each pixel against
+    pub threshold_radius: u32,
+    /// How far below the neighbourhood mean a pixel must be to count as ink
+    pub threshold_offset: i32,
+    pub sharpen_sigma: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PreprocessStep {
+    Grayscale,
+    /// Stretch the darkest and lightest 1% of pixels to black and white
+    ContrastStretch,
+    /// Adaptive binarisation against the local mean, which copes with uneven lighting
+    Threshold,
+    /// 3x3 median filter
+    Denoise,
+    Sharpen,
+}
+
+/// Tesseract settings
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OcrConfig {
+    pub language: String,
+    /// Page segmentation mode, 0-13; receipts usually do best with 4 or 6
+    pub psm: Option<u8>,
+    /// Only recognise these characters
+    pub whitelist: Option<String>,
+    /// Resolution hint for images without one
+    pub dpi: Option<i32>,
+    /// Any other tesseract variables, passed through as is
+    pub variables: BTreeMap<String, String>,
+    /// Purchases whose code or price were read with less confidence than this (0-100) are flagged
+    pub min_confidence: f32,
+}
+
+/// Extra OCR passes for receipts that don't parse well. The pass that reads the store and the
+/// most item lines is kept.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Retry when fewer item lines than this are matched, or the store isn't recognised
+    pub min_items: usize,
+    /// Tried in order after the normal settings until one matches `min_items`
+    pub attempts: Vec<RetryAttempt>,
+    /// Every pass is logged here, to see which settings work for which store. Defaults to
+    /// `ocr_attempts.tsv` next to the done file.
+    pub stats_file: Option<PathBuf>,
+}
+
+/// Where images go once their receipt is scanned
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    /// Archive root, laid out as `YYYY/MM/<store>/`. Images stay in `image_dir` when unset.
+    pub dir: Option<PathBuf>,
+    pub mode: ArchiveMode,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveMode {
+    /// Take the image out of `image_dir`
+    #[default]
+    Move,
+    /// Leave the original in `image_dir` too
+    Copy,
+}
+
+/// Settings replaced for one retry; anything unset keeps the store's normal setting
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryAttempt {
+    pub scale: Option<f32>,
+    pub steps: Option<Vec<PreprocessStep>>,
+    pub psm: Option<u8>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoreConfig {
+    /// Overrides keys of the top level `[preprocess]` for receipts from this store
+    pub preprocess: Option<StorePreprocess>,
+    /// Overrides keys of the top level `[ocr]` for receipts from this store
+    pub ocr: Option<StoreOcr>,
+}
+
+/// Preprocessing for one store; anything unset keeps the top level setting
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorePreprocess {
+    pub crop: Option<bool>,
+    pub deskew: Option<bool>,
+    pub scale: Option<f32>,
+    pub steps: Option<Vec<PreprocessStep>>,
+    pub threshold_radius: Option<u32>,
+    pub threshold_offset: Option<i32>,
+    pub sharpen_sigma: Option<f32>,
+}
+
+/// Tesseract settings for one store; anything unset keeps the top level setting
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoreOcr {
+    pub language: Option<String>,
+    pub psm: Option<u8>,
+    pub whitelist: Option<String>,
+    pub dpi: Option<i32>,
+    /// Added to the top level variables, replacing any with the same name
+    pub variables: BTreeMap<String, String>,
+    pub min_confidence: Option<f32>,
+}
+
+impl Default for OcrConfig {
+    fn default() -> Self {
+        Self {
+            language: "eng".to_owned(),
+            psm: None,
+            whitelist: None,
+            dpi: None,
+            variables: BTreeMap::new(),
+            min_confidence: 60.0,
+        }
+    }
+}
+
+impl Default for ImagesConfig {
+    fn default() -> Self {
+        Self {
+            recursive: false,
+            extensions: ["jpg", "jpeg", "png", "tif", "tiff", "bmp", "webp", "pdf", "html", "htm"]
+                .into_iter().map(|e| e.to_owned()).collect(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        use PreprocessStep::*;
+        Self {
+            min_items: 2,
+            attempts: vec![
+                RetryAttempt { steps: Some(vec![Grayscale, ContrastStretch, Threshold]), ..RetryAttempt::default() },
+                RetryAttempt { scale: Some(2.0), psm: Some(4), ..RetryAttempt::default() },
+                RetryAttempt { scale: Some(2.5), steps: Some(vec![Grayscale, Denoise, Threshold]), psm: Some(6) },
+            ],
+            stats_file: None,
+        }
+    }
+}
+
+impl StorePreprocess {
+    /// `pre` with this store's settings swapped in
+    pub fn apply(&self, pre: &PreprocessConfig) -> PreprocessConfig {
+        let mut pre = pre.clone();
+        if let Some(crop) = self.crop { pre.crop = crop; }
+        if let Some(deskew) = self.deskew { pre.deskew = deskew; }
+        if let Some(scale) = self.scale { pre.scale = scale; }
+        if let Some(steps) = &self.steps { pre.steps = steps.clone(); }
+        if let Some(radius) = self.threshold_radius { pre.threshold_radius = radius; }
+        if let Some(offset) = self.threshold_offset { pre.threshold_offset = offset; }
+        if let Some(sigma) = self.sharpen_sigma { pre.sharpen_sigma = sigma; }
+        pre
+    }
+}
+
+impl StoreOcr {
+    /// `ocr` with this store's settings swapped in
+    pub fn apply(&self, ocr: &OcrConfig) -> OcrConfig {
+        let mut ocr = ocr.clone();
+        if let Some(language) = &self.language { ocr.language = language.clone(); }
+        if let Some(psm) = self.psm { ocr.psm = Some(psm); }
+        if let Some(whitelist) = &self.whitelist { ocr.whitelist = Some(whitelist.clone()); }
+        if let Some(dpi) = self.dpi { ocr.dpi = Some(dpi); }
+        ocr.variables.extend(self.variables.iter().map(|(k, v)| (k.clone(), v.clone())));
+        if let Some(min) = self.min_confidence { ocr.min_confidence = min; }
+        ocr
+    }
+}
+
+impl RetryAttempt {
+    /// `pre` and `ocr` with this attempt's settings swapped in
+    pub fn apply(&self, pre: &PreprocessConfig, ocr: &OcrConfig) -> (PreprocessConfig, OcrConfig) {
+        let mut pre = pre.clone();
+        let mut ocr = ocr.clone();
+        if let Some(scale) = self.scale { pre.scale = scale; }
+        if let Some(steps) = &self.steps { pre.steps = steps.clone(); }
+        if let Some(psm) = self.psm { ocr.psm = Some(psm); }
+        (pre, ocr)
+    }
+}
+
+impl Default for PreprocessConfig {
+    fn default() -> Self {
+        Self {
+            crop: false,
+            deskew: false,
+            scale: 1.5,
+            steps: Vec::new(),
+            threshold_radius: 15,
+            threshold_offset: 10,
+            sharpen_sigma: 1.0,
+        }
+    }
+}
+
+/// Accounts used when exporting to plain-text accounting journals
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportConfig {
+    /// Account for purchases whose tags have no entry in `accounts`
+    pub default_account: String,
+    /// Account the receipt was paid from; balances each transaction
+    pub payment_account: String,
+    /// Tag to account, e.g. `produce = "Expenses:Groceries:Produce"`. The first tag with an entry wins.
+    pub accounts: HashMap<String, String>,
+    /// Commodity written before amounts in ledger/hledger journals
+    pub commodity: String,
+    /// Currency written after amounts in beancount journals
+    pub beancount_currency: String,
+}
+
+/// How to read bank/credit card statement CSVs and match their charges to receipts
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BankConfig {
+    pub date_column: String,
+    pub description_column: String,
+    pub amount_column: String,
+    /// chrono format of the date column
+    pub date_format: String,
+    /// Set when the statement lists charges as negative amounts
+    pub negate_amounts: bool,
+    /// Store name to substrings of the charge description that identify it
+    pub stores: BTreeMap<String, Vec<String>>,
+    /// Days a charge may post before or after the receipt date
+    pub date_window_days: i64,
+    /// Charges match receipts within the larger of these two, to allow for tax and coupons
+    pub amount_tolerance: f64,
+    pub amount_tolerance_pct: f64,
+}
+
+impl Default for BankConfig {
+    fn default() -> Self {
+        let stores = [
+            ("Costco", vec!["COSTCO"]),
+            ("Fred Meyer", vec!["FRED MEYER", "FRED-MEYER", "FREDMEYER"]),
+            ("WinCo", vec!["WINCO"]),
+        ];
+        Self {
+            date_column: "Date".to_owned(),
+            description_column: "Description".to_owned(),
+            amount_column: "Amount".to_owned(),
+            date_format: "%m/%d/%Y".to_owned(),
+            negate_amounts: false,
+            stores: stores.into_iter()
+                .map(|(store, patterns)| (store.to_owned(), patterns.into_iter().map(|p| p.to_owned()).collect()))
+                .collect(),
+            date_window_days: 3,
+            amount_tolerance: 1.0,
+            amount_tolerance_pct: 10.0,
+        }
+    }
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        Self {
+            default_account: "Expenses:Groceries".to_owned(),
+            payment_account: "Liabilities:CreditCard".to_owned(),
+            accounts: HashMap::new(),
+            commodity: "$".to_owned(),
+            beancount_currency: "USD".to_owned(),
+        }
+    }
 }
 
 impl Config {
@@ -37,14 +367,77 @@ impl Config {
     }
 
     fn default_config() -> Result<Self> {
-        let data_dir = data_dir_path()?;
-        Ok(Self {
+        Ok(Self::in_data_dir(&data_dir_path()?))
+    }
+
+    /// Default layout with every data file under `data_dir`
+    pub fn in_data_dir(data_dir: &Path) -> Self {
+        Self {
             image_dir: data_dir.join("images"),
             upscaled_image_dir: data_dir.join("upscaled"),
             done_file: data_dir.join("done"),
             rules_file: data_dir.join("rules"),
             purchases_file: data_dir.join("purchases"),
-        })
+            ocr_cache_dir: None,
+            receipt_report_dir: None,
+            images: ImagesConfig::default(),
+            export: ExportConfig::default(),
+            bank: BankConfig::default(),
+            preprocess: PreprocessConfig::default(),
+            ocr: OcrConfig::default(),
+            retry: RetryConfig::default(),
+            archive: ArchiveConfig::default(),
+            stores: HashMap::new(),
+        }
+    }
+
+    pub fn ocr_cache_dir(&self) -> PathBuf {
+        self.ocr_cache_dir.clone()
+            .unwrap_or_else(|| self.done_file.with_file_name("ocr_cache"))
+    }
+
+    pub fn receipt_report_dir(&self) -> PathBuf {
+        self.receipt_report_dir.clone()
+            .unwrap_or_else(|| self.done_file.with_file_name("receipt_reports"))
+    }
+
+    pub fn retry_stats_file(&self) -> PathBuf {
+        self.retry.stats_file.clone()
+            .unwrap_or_else(|| self.done_file.with_file_name("ocr_attempts.tsv"))
+    }
+
+    /// Holds the next purchase id, so ids of deleted purchases aren't handed out again
+    pub fn next_id_file(&self) -> PathBuf {
+        let mut name = self.purchases_file.file_name().unwrap_or_default().to_os_string();
+        name.push(".next_id");
+        self.purchases_file.with_file_name(name)
+    }
+
+    /// OCR settings for a store: the top level ones, with any the store sets replaced. `store` is
+    /// a store key.
+    pub fn ocr_for(&self, store: Option<&str>) -> OcrConfig {
+        match store.and_then(|s| self.stores.get(s)).and_then(|s| s.ocr.as_ref()) {
+            Some(ocr) => ocr.apply(&self.ocr),
+            None => self.ocr.clone(),
+        }
+    }
+
+    /// Set tesseract variables for every store, overriding any from the config file
+    pub fn set_ocr_variables(&mut self, vars: &[(String, String)]) {
+        let variables = std::iter::once(&mut self.ocr.variables)
+            .chain(self.stores.values_mut().filter_map(|s| s.ocr.as_mut().map(|o| &mut o.variables)));
+        for v in variables {
+            v.extend(vars.iter().cloned());
+        }
+    }
+
+    /// Preprocessing for a store: the top level chain, with any settings the store sets replaced.
+    /// `store` is a store key.
+    pub fn preprocess_for(&self, store: Option<&str>) -> PreprocessConfig {
+        match store.and_then(|s| self.stores.get(s)).and_then(|s| s.preprocess.as_ref()) {
+            Some(pre) => pre.apply(&self.preprocess),
+            None => self.preprocess.clone(),
+        }
     }
 
     pub fn init() -> Result<()> {
@@ -105,3 +498,102 @@ fn data_dir_path() -> Result<PathBuf> {
         Ok(PathBuf::from(home).join(".local").join("share").join("itemizer"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minimal_config_gets_defaults() {
+        let config: Config = toml::from_str(r#"
+            image_dir = "/i"
+            upscaled_image_dir = "/u"
+            done_file = "/d"
+            rules_file = "/r"
+            purchases_file = "/p"
+        "#).unwrap();
+        assert_eq!(config.preprocess.scale, 1.5);
+        assert!(config.preprocess.steps.is_empty());
+        assert!(config.stores.is_empty());
+    }
+
+    #[test]
+    fn test_store_preprocess_override() {
+        let config: Config = toml::from_str(r#"
+            image_dir = "/i"
+            upscaled_image_dir = "/u"
+            done_file = "/d"
+            rules_file = "/r"
+            purchases_file = "/p"
+
+            [preprocess]
+            crop = true
+            steps = ["grayscale"]
+            threshold_radius = 20
+
+            [stores.costco.preprocess]
+            scale = 2.0
+            steps = ["grayscale", "contrast_stretch", "threshold"]
+        "#).unwrap();
+        assert_eq!(config.preprocess_for(None).steps, vec![PreprocessStep::Grayscale]);
+        assert_eq!(config.preprocess_for(Some("winco")).steps, vec![PreprocessStep::Grayscale]);
+        let costco = config.preprocess_for(Some("costco"));
+        assert_eq!(costco.scale, 2.0);
+        assert_eq!(costco.steps.len(), 3);
+        // Keys the store doesn't set come from the top level
+        assert!(costco.crop);
+        assert_eq!(costco.threshold_radius, 20);
+    }
+
+    #[test]
+    fn test_store_ocr_override_and_cli_variables() {
+        let mut config: Config = toml::from_str(r#"
+            image_dir = "/i"
+            upscaled_image_dir = "/u"
+            done_file = "/d"
+            rules_file = "/r"
+            purchases_file = "/p"
+
+            [ocr]
+            psm = 6
+            dpi = 300
+
+            [ocr.variables]
+            textord_heavy_nr = "0"
+            tessedit_do_invert = "0"
+
+            [stores.winco.ocr]
+            psm = 4
+            whitelist = "0123456789.ABCDEFGHIJKLMNOPQRSTUVWXYZ "
+
+            [stores.winco.ocr.variables]
+            tessedit_do_invert = "1"
+        "#).unwrap();
+        config.set_ocr_variables(&[("textord_heavy_nr".to_owned(), "1".to_owned())]);
+
+        assert_eq!(config.ocr_for(Some("costco")).psm, Some(6));
+        assert_eq!(config.ocr_for(Some("costco")).language, "eng");
+        let winco = config.ocr_for(Some("winco"));
+        assert_eq!(winco.psm, Some(4));
+        assert!(winco.whitelist.is_some());
+        // Keys the store doesn't set come from the top level
+        assert_eq!(winco.dpi, Some(300));
+        assert_eq!(winco.language, "eng");
+        assert_eq!(winco.variables["tessedit_do_invert"], "1");
+        assert_eq!(winco.variables["textord_heavy_nr"], "1");
+        assert_eq!(config.ocr_for(None).variables["textord_heavy_nr"], "1");
+    }
+
+    #[test]
+    fn test_retry_attempt_overrides() {
+        let attempt = RetryAttempt { psm: Some(4), ..RetryAttempt::default() };
+        let pre = PreprocessConfig { steps: vec![PreprocessStep::Grayscale], ..PreprocessConfig::default() };
+        let ocr = OcrConfig { psm: Some(6), dpi: Some(300), ..OcrConfig::default() };
+
+        let (pre, ocr) = attempt.apply(&pre, &ocr);
+        assert_eq!(pre.steps, vec![PreprocessStep::Grayscale]);
+        assert_eq!(pre.scale, 1.5);
+        assert_eq!(ocr.psm, Some(4));
+        assert_eq!(ocr.dpi, Some(300));
+    }
+}
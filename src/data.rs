@@ -5,8 +5,9 @@ use crate::config::Config;
 use anyhow::{Context, Result, bail};
 use chrono::NaiveDate;
 use regex::Regex;
+use sha2::{Digest, Sha256};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::cmp::max;
 use std::ops::{Deref, DerefMut};
 use std::fs::File;
@@ -18,6 +19,13 @@ pub struct FileItemizer {
     pub maps: ItemMaps,
     pub purchases: Purchases,
     pub current_date: NaiveDate,
+    pub current_store: Option<String>,
+    pub current_receipt: Option<String>,
+    /// Id for the next purchase; never lowered, so a deleted purchase's id isn't reused
+    next_id: u64,
+    /// Purchases dropped by `remove_receipt`, so reading the receipt again gives each line back its
+    /// id. Cleared with `clear_replaced` once the receipt is read.
+    replaced: Vec<Purchase>,
 }
 
 #[derive(Clone, Debug)]
@@ -32,21 +40,56 @@ pub struct ItemMaps {
     pub descr: HashMap<String, usize>,
     pub rules: Vec<ItemRule>,
 }
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Purchase {
+    /// Stable number for editing from the command line; 0 until assigned
+    pub id: u64,
     pub name: String,
     pub tags: Vec<String>,
     pub price: f64,
     pub date: NaiveDate,
     pub code: Option<u64>,
+    pub store: Option<String>,
+    /// Content hash of the receipt image this purchase was scanned from
+    pub receipt: Option<String>,
+    /// Lowest OCR confidence (0-100) of the code and price, when it was low enough to flag
+    pub confidence: Option<u8>,
+    /// Entered with `itemizer add` rather than scanned
+    pub manual: bool,
+    /// Changed with `itemizer purchases edit`, so rescanning its receipt would lose the change
+    pub edited: bool,
+}
+
+/// A purchase entered by hand, e.g. cash at a market or from a lost receipt
+#[derive(Clone, Debug, Default)]
+pub struct ManualEntry {
+    pub date: NaiveDate,
+    pub store: Option<String>,
+    pub price: f64,
+    /// Overrides the name of the rule found by `code` or `desc`
+    pub name: Option<String>,
+    /// Overrides the tags of the rule found by `code` or `desc`
+    pub tags: Option<Vec<String>>,
+    pub code: Option<u64>,
+    pub desc: Option<String>,
 }
 pub struct Purchases(pub Vec<Purchase>);
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ReceiptType {
     FredMeyer,
     Costco,
     WinCo,
 }
+/// How one line of receipt text was read
+#[derive(Clone, Debug, PartialEq)]
+pub enum LineParse {
+    /// Doesn't look like an item line
+    NoMatch,
+    /// Looks like an item line, but a field couldn't be read
+    Skipped(String),
+    Item(u64, String, f64),
+}
 pub struct Receipt {
     pub store: ReceiptType,
     pub text: String,
@@ -63,6 +106,12 @@ pub fn image_done(image: &str, done_file: &Path) -> Result<bool> {
     }
 }
 
+/// Stable identifier for a receipt, taken from the image contents so renames don't change it
+pub fn receipt_id(bytes: &[u8]) -> String {
+    let digest = Sha256::digest(bytes);
+    digest.iter().take(6).map(|b| format!("{:02x}", b)).collect()
+}
+
 pub fn split_tags(tags: &str) -> Vec<String> {
     tags
         .split(",")
@@ -83,6 +132,39 @@ impl ItemRule {
     }
 }
 
+impl ReceiptType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReceiptType::FredMeyer => "Fred Meyer",
+            ReceiptType::Costco => "Costco",
+            ReceiptType::WinCo => "WinCo",
+        }
+    }
+
+    /// Key used for this store in config tables
+    pub fn key(&self) -> &'static str {
+        match self {
+            ReceiptType::FredMeyer => "fredmeyer",
+            ReceiptType::Costco => "costco",
+            ReceiptType::WinCo => "winco",
+        }
+    }
+
+    /// Guess the store from an image file name like `2024-07-21-costco.jpg`, before any OCR
+    pub fn from_hint(file_name: &str) -> Option<Self> {
+        let lower = file_name.to_lowercase().replace(['-', '_', ' '], "");
+        if lower.contains("fredmeyer") {
+            Some(ReceiptType::FredMeyer)
+        } else if lower.contains("costco") {
+            Some(ReceiptType::Costco)
+        } else if lower.contains("winco") {
+            Some(ReceiptType::WinCo)
+        } else {
+            None
+        }
+    }
+}
+
 impl Receipt {
     pub fn new(text: String) -> Result<Self> {
         let fm_list = ["fredmeyer", "fred meyer"];
@@ -110,46 +192,59 @@ impl Receipt {
         Ok(Self { store, text, re })
     }
 
-    pub fn get_fields(&self, line: &str) -> Option<(u64, String, f64)> {
-        let caps = self.re.captures(line)?;
+    /// Capture group numbers of the code, description and price for this store's pattern
+    fn field_groups(&self) -> (usize, usize, usize) {
         match self.store {
-            ReceiptType::Costco |
-            ReceiptType::FredMeyer => {
-                let code: u64 = match caps[1].parse() {
-                    Ok(c) => c,
-                    Err(_) => {
-                        eprintln!("  Skipping line, bad code '{}': [{}]", &caps[1], line);
-                        return None;
-                    }
-                };
-                let price: f64 = match caps[3].replace(",", ".").parse() {
-                    Ok(p) => p,
-                    Err(_) => {
-                        eprintln!("  Skipping line, bad price '{}': [{}]", &caps[3], line);
-                        return None;
-                    }
-                };
-                Some((code, caps[2].to_owned(), price))
-            },
-            ReceiptType::WinCo => {
-                let code: u64 = match caps[2].parse() {
-                    Ok(c) => c,
-                    Err(_) => {
-                        eprintln!("  Skipping line, bad code '{}': [{}]", &caps[2], line);
-                        return None;
-                    }
-                };
-                let price: f64 = match caps[3].replace(",", ".").parse() {
-                    Ok(p) => p,
-                    Err(_) => {
-                        eprintln!("  Skipping line, bad price '{}': [{}]", &caps[3], line);
-                        return None;
-                    }
-                };
-                Some((code, caps[1].to_owned(), price))
-            },
+            ReceiptType::Costco | ReceiptType::FredMeyer => (1, 2, 3),
+            ReceiptType::WinCo => (2, 1, 3),
         }
     }
+
+    /// The code and price exactly as they appear in the line, before parsing
+    pub fn field_text<'a>(&self, line: &'a str) -> Option<(&'a str, &'a str)> {
+        let caps = self.re.captures(line)?;
+        let (code, _, price) = self.field_groups();
+        Some((caps.get(code)?.as_str(), caps.get(price)?.as_str()))
+    }
+
+    pub fn get_fields(&self, line: &str) -> Option<(u64, String, f64)> {
+        match self.parse_line(line) {
+            LineParse::Item(code, desc, price) => Some((code, desc, price)),
+            LineParse::Skipped(reason) => {
+                log::debug!("Skipping line, {}: [{}]", reason, line);
+                None
+            }
+            LineParse::NoMatch => None,
+        }
+    }
+
+    /// Read a line with this store's pattern, saying why it was skipped if it looks like an item
+    /// but a field doesn't parse
+    pub fn parse_line(&self, line: &str) -> LineParse {
+        let Some(caps) = self.re.captures(line) else {
+            return LineParse::NoMatch;
+        };
+        let (code, desc, price) = self.field_groups();
+        let Ok(code_num) = caps[code].parse::<u64>() else {
+            return LineParse::Skipped(format!("bad code '{}'", &caps[code]));
+        };
+        let Ok(price_num) = caps[price].replace(",", ".").parse::<f64>() else {
+            return LineParse::Skipped(format!("bad price '{}'", &caps[price]));
+        };
+        LineParse::Item(code_num, caps[desc].to_owned(), price_num)
+    }
+
+    /// The subtotal printed on the receipt, or the total when there is no subtotal line
+    pub fn printed_total(&self) -> Option<f64> {
+        let amount = Regex::new(r"(\d{1,5}[.,]\d\d)\s*[A-Z-]*\s*$").unwrap();
+        // Nearest the bottom of the receipt
+        let find = |label: &Regex| self.text.lines().rev()
+            .filter(|l| label.is_match(l))
+            .filter_map(|l| amount.captures(l))
+            .find_map(|c| c[1].replace(",", ".").parse().ok());
+        find(&Regex::new(r"(?i)sub\s*-?\s*total").unwrap())
+            .or_else(|| find(&Regex::new(r"(?i)^\W*(\*+\s*)?(total|balance)\b").unwrap()))
+    }
 }
 
 impl ItemMaps {
@@ -178,14 +273,14 @@ impl ItemMaps {
             }
             let sg: Vec<&str> = group.lines().collect();
             if sg.len() < 3 {
-                eprintln!("WARNING: skipping malformed rules block (need 3-4 lines, got {}): {:?}", sg.len(), sg);
+                log::warn!("skipping malformed rules block (need 3-4 lines, got {}): {:?}", sg.len(), sg);
                 continue;
             }
 
             let code: u64 = match sg[0].parse() {
                 Ok(c) => c,
                 Err(_) => {
-                    eprintln!("WARNING: skipping rules block with invalid code '{}': {:?}", sg[0], sg);
+                    log::warn!("skipping rules block with invalid code '{}': {:?}", sg[0], sg);
                     continue;
                 }
             };
@@ -200,7 +295,7 @@ impl ItemMaps {
             }
 
             if codes.contains_key(&item.code) || descr.contains_key(&item.desc) {
-                eprintln!("WARNING: duplicate item found in rules list: [{:?}]", item);
+                log::warn!("duplicate item found in rules list: [{:?}]", item);
             }
             codes.insert(item.code, rules.len());
             descr.insert(item.desc.clone(), rules.len());
@@ -230,35 +325,98 @@ impl Purchases {
         let mut v = Vec::new();
         for (i, line) in text.lines().enumerate() {
             let parts: Vec<&str> = line.split("|").map(|s| s.trim()).collect();
-            if parts.len() != 4 {
-                eprintln!("WARNING: skipping malformed purchase line {} (expected 4 fields, got {}): {}", i + 1, parts.len(), line);
+            if parts.len() < 4 {
+                log::warn!("skipping malformed purchase line {} (expected at least 4 fields, got {}): {}", i + 1, parts.len(), line);
                 continue;
             }
 
             let date: NaiveDate = match parts[0].parse() {
                 Ok(d) => d,
                 Err(_) => {
-                    eprintln!("WARNING: skipping purchase line {} with bad date '{}': {}", i + 1, parts[0], line);
+                    log::warn!("skipping purchase line {} with bad date '{}': {}", i + 1, parts[0], line);
                     continue;
                 }
             };
             let price: f64 = match parts[1].parse() {
                 Ok(p) => p,
                 Err(_) => {
-                    eprintln!("WARNING: skipping purchase line {} with bad price '{}': {}", i + 1, parts[1], line);
+                    log::warn!("skipping purchase line {} with bad price '{}': {}", i + 1, parts[1], line);
                     continue;
                 }
             };
             let name = parts[2].to_owned();
             let tags = split_tags(parts[3]);
+            // Store and receipt columns were added later; older files only have the first four
+            let optional = |idx: usize| parts.get(idx).filter(|s| !s.is_empty()).map(|s| s.to_string());
+            let store = optional(4);
+            let receipt = optional(5);
+            let mut confidence = None;
+            let mut manual = false;
+            let mut edited = false;
+            for flag in optional(6).as_deref().map(split_tags).unwrap_or_default() {
+                if let Some(conf) = flag.strip_prefix("conf=") {
+                    confidence = conf.parse().ok();
+                } else if flag == "manual" {
+                    manual = true;
+                } else if flag == "edited" {
+                    edited = true;
+                }
+            }
+
+            let id = optional(7).and_then(|id| id.parse().ok()).unwrap_or(0);
 
-            v.push(Purchase { price, name, tags, date, code: None });
+            v.push(Purchase { id, price, name, tags, date, code: None, store, receipt, confidence, manual, edited });
         }
 
+        // Files from before the id column get ids in file order, saved with the next change
+        let first = v.iter().map(|p| p.id).max().unwrap_or(0) + 1;
+        for (id, p) in (first..).zip(v.iter_mut().filter(|p| p.id == 0)) {
+            p.id = id;
+        }
         Ok(Purchases(v))
     }
 }
 
+impl Purchases {
+    pub fn get_mut(&mut self, id: u64) -> Result<&mut Purchase> {
+        self.iter_mut().find(|p| p.id == id).with_context(|| format!("No purchase with id {}", id))
+    }
+
+    /// Remove the purchase with `id`, returning it
+    pub fn delete(&mut self, id: u64) -> Result<Purchase> {
+        let idx = self.iter().position(|p| p.id == id).with_context(|| format!("No purchase with id {}", id))?;
+        Ok(self.remove(idx))
+    }
+}
+
+impl Purchase {
+    /// Whether this purchase was, or may have been, read from the receipt
+    pub fn read_from(&self, receipt: &str, date: NaiveDate, store: &str) -> bool {
+        self.receipt.as_deref() == Some(receipt) || self.legacy_of(date, store)
+    }
+
+    /// Whether this purchase was recorded before receipt ids existed, on the date of a receipt from
+    /// `store`. Purchases that old with no store could be from any receipt that day.
+    pub fn legacy_of(&self, date: NaiveDate, store: &str) -> bool {
+        self.receipt.is_none() && !self.manual && self.date == date && self.store.as_deref().is_none_or(|s| s == store)
+    }
+
+    /// Extra markers stored in the last column of the purchases file
+    pub fn flags(&self) -> String {
+        let mut flags = Vec::new();
+        if let Some(conf) = self.confidence {
+            flags.push(format!("conf={}", conf));
+        }
+        if self.manual {
+            flags.push("manual".to_owned());
+        }
+        if self.edited {
+            flags.push("edited".to_owned());
+        }
+        flags.join(", ")
+    }
+}
+
 impl Deref for Purchases {
     type Target = Vec<Purchase>;
     fn deref(&self) -> &Self::Target {
@@ -275,45 +433,181 @@ impl FileItemizer {
     pub fn new(config: Config) -> Result<Self> {
         let maps = ItemMaps::init(&config.rules_file)?;
         let purchases = Purchases::init(&config.purchases_file)?;
+        let next_id = read_next_id(&config.next_id_file())?
+            .max(purchases.iter().map(|p| p.id).max().unwrap_or(0) + 1);
         Ok(Self {
             config,
             maps,
             purchases,
             current_date: NaiveDate::from_ymd_opt(2001, 1, 1).unwrap(),
+            current_store: None,
+            current_receipt: None,
+            next_id,
+            replaced: Vec::new(),
         })
     }
 
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
     pub fn set_date(&mut self, date: NaiveDate) {
         self.current_date = date;
     }
 
-    pub fn process_purchase(&mut self, code: u64, desc: String, price: f64) {
+    pub fn set_receipt(&mut self, store: &str, receipt: &str) {
+        self.current_store = Some(store.to_owned());
+        self.current_receipt = Some(receipt.to_owned());
+    }
+
+    pub fn process_purchase(&mut self, code: u64, desc: String, price: f64) -> &mut Purchase {
         let idx = if self.maps.codes.contains_key(&code) {
             self.maps.codes[&code]
         } else if self.maps.descr.contains_key(&desc) {
             self.maps.descr[&desc]
         } else {
-            println!("No item for code/desc/price: [{}]/[{}]/[{}]", code, desc, price);
-            println!("Inserting entry into rules file");
+            log::info!("No item for code/desc/price: [{}]/[{}]/[{}], adding it to the rules file", code, desc, price);
             self.maps.codes.insert(code, self.maps.rules.len());
             self.maps.descr.insert(desc.clone(), self.maps.rules.len());
             self.maps.rules.push(ItemRule { code, desc, name: "UNKNOWN".to_owned(), tags: vec!["EXCLUDE".to_owned()] });
             self.maps.rules.len() - 1
         };
 
+        // A line read again by a rescan keeps its id. Codes aren't saved, so purchases loaded from
+        // the file are matched by the name they were saved with; prices are saved to the cent.
+        let rule = &self.maps.rules[idx];
+        let saved_name = if rule.name == "UNKNOWN" { &rule.desc } else { &rule.name };
+        let same_item = |p: &Purchase| (p.price - price).abs() < 0.005 && match p.code {
+            Some(c) => c == code,
+            None => p.name == *saved_name,
+        };
+        let id = match self.replaced.iter().position(same_item) {
+            Some(i) => self.replaced.remove(i).id,
+            None => self.next_id(),
+        };
         self.purchases.push(Purchase {
+            id,
             date: self.current_date,
             price,
             name: self.maps.rules[idx].name.clone(),
             tags: self.maps.rules[idx].tags.clone(),
             code: Some(code),
+            store: self.current_store.clone(),
+            receipt: self.current_receipt.clone(),
+            confidence: None,
+            manual: false,
+            edited: false,
         });
+        self.purchases.last_mut().unwrap()
+    }
+
+    /// Record a purchase entered by hand. With a code or description, the name and tags come from
+    /// the matching rule unless given.
+    pub fn add_manual(&mut self, entry: ManualEntry) -> Result<&Purchase> {
+        let rule = match (entry.code, &entry.desc) {
+            (Some(code), _) if self.maps.codes.contains_key(&code) => Some(&self.maps.rules[self.maps.codes[&code]]),
+            (_, Some(desc)) if self.maps.descr.contains_key(desc) => Some(&self.maps.rules[self.maps.descr[desc]]),
+            (None, None) => None,
+            _ if entry.name.is_some() => None,
+            _ => {
+                let wanted: Vec<String> = [entry.code.map(|c| format!("code {}", c)), entry.desc.as_ref().map(|d| format!("description '{}'", d))]
+                    .into_iter().flatten().collect();
+                bail!("No rule matches {}; give a name instead", wanted.join(" or "));
+            }
+        };
+        let Some(name) = entry.name.or_else(|| rule.map(|r| r.name.clone())) else {
+            bail!("A manual purchase needs a name, or a code or description from the rules file");
+        };
+        let tags = entry.tags.or_else(|| rule.map(|r| r.tags.clone())).unwrap_or_default();
+        let code = rule.map(|r| r.code);
+
+        let id = self.next_id();
+        self.purchases.push(Purchase {
+            id,
+            date: entry.date,
+            price: entry.price,
+            name,
+            tags,
+            code,
+            store: entry.store,
+            receipt: None,
+            confidence: None,
+            manual: true,
+            edited: false,
+        });
+        Ok(self.purchases.last().unwrap())
+    }
+
+    /// Drop every purchase read from a receipt, returning how many there were. With `legacy`, so
+    /// does every purchase recorded before receipt ids existed on the receipt's date (see
+    /// `Purchase::legacy_of`). Their ids go to the purchases next read with the same item and price.
+    pub fn remove_receipt(&mut self, receipt: &str, date: NaiveDate, store: &str, legacy: bool) -> usize {
+        let (removed, kept): (Vec<Purchase>, Vec<Purchase>) = std::mem::take(&mut self.purchases.0)
+            .into_iter()
+            .partition(|p| p.receipt.as_deref() == Some(receipt) || (legacy && p.legacy_of(date, store)));
+        self.purchases.0 = kept;
+        let count = removed.len();
+        self.replaced = removed;
+        count
+    }
+
+    /// Forget the ids of purchases dropped by `remove_receipt` and not read again, so they don't go
+    /// to the next receipt
+    pub fn clear_replaced(&mut self) {
+        self.replaced.clear();
+    }
+
+    /// How many purchases recorded before receipt ids existed could be from a receipt
+    pub fn legacy_count(&self, date: NaiveDate, store: &str) -> usize {
+        self.purchases.iter().filter(|p| p.legacy_of(date, store)).count()
+    }
+
+    /// Drop the purchases recorded before receipt ids existed that were read again from `receipt`,
+    /// giving their ids to the new ones. Only done when every purchase from the receipt has a
+    /// match by name and price, so items from other receipts that day are left alone; returns
+    /// whether it was.
+    pub fn replace_legacy(&mut self, receipt: &str, date: NaiveDate, store: &str) -> bool {
+        let mut legacy: Vec<usize> = (0..self.purchases.len())
+            .filter(|&i| self.purchases[i].legacy_of(date, store))
+            .collect();
+        let mut pairs = Vec::new();
+        for (i, p) in self.purchases.iter().enumerate().filter(|(_, p)| p.receipt.as_deref() == Some(receipt)) {
+            let name = self.saved_name(p);
+            let found = legacy.iter().position(|&l| {
+                let old = &self.purchases[l];
+                old.name == name && (old.price - p.price).abs() < 0.005
+            });
+            let Some(found) = found else {
+                return false;
+            };
+            pairs.push((i, legacy.remove(found)));
+        }
+
+        for &(new, old) in &pairs {
+            self.purchases[new].id = self.purchases[old].id;
+        }
+        let old: HashSet<usize> = pairs.iter().map(|&(_, old)| old).collect();
+        let mut i = 0;
+        self.purchases.retain(|_| {
+            i += 1;
+            !old.contains(&(i - 1))
+        });
+        true
     }
 
     pub fn purchases(&self) -> &Purchases {
         &self.purchases
     }
 
+    /// Name written to the purchases file: the receipt description for items with no rule yet
+    fn saved_name<'a>(&'a self, p: &'a Purchase) -> &'a str {
+        match p.code {
+            Some(code) if p.name == "UNKNOWN" => &self.maps.rules[self.maps.codes[&code]].desc,
+            _ => &p.name,
+        }
+    }
+
     pub fn get_max_lengths(&self) -> (usize, usize, usize) {
         let mut price_max = 0;
         let mut name_max = 0;
@@ -321,7 +615,7 @@ impl FileItemizer {
         for p in &self.purchases.0 {
             price_max = max(price_max, p.price.to_string().len());
             name_max = max(name_max, p.name.len());
-            let mut this_tags_len = max(0, p.tags.len() as i64 - 1 * 2) as usize;
+            let mut this_tags_len = max(0, (p.tags.len() as i64 - 1) * 2) as usize;
             for tag in &p.tags {
                 this_tags_len += tag.len();
             }
@@ -330,41 +624,76 @@ impl FileItemizer {
         (price_max, name_max, tags_max)
     }
 
+    /// Write the rules, then the purchases. Each file is replaced in one step, so an interrupted
+    /// save leaves the previous version intact.
     pub fn save_to_disk(&self) -> Result<()> {
-        // Purchases File
-        let (price_max, name_max, _tags_max) = self.get_max_lengths();
-        let mut file = File::create(&self.config.purchases_file)
-            .with_context(|| format!("Failed to create purchases file: {}", self.config.purchases_file.display()))?;
-        for p in &self.purchases.0 {
-            // Write receipt description for UNKNOWN items so they're identifiable
-            let name = if p.name == "UNKNOWN" && p.code.is_some() {
-                &self.maps.rules[self.maps.codes[&p.code.unwrap()]].desc
-            } else {
-                &p.name
-            };
-
-            let s = format!("{} | {:>price_max$.2} | {:<name_max$} | {}\n",
-                p.date, p.price, name, p.tags.join(", "));
-            file.write_all(s.as_bytes())?;
-        }
-
         // Rules File
-        let mut file = File::create(&self.config.rules_file)
-            .with_context(|| format!("Failed to create rules file: {}", self.config.rules_file.display()))?;
+        let mut rules = String::new();
         for r in &self.maps.rules {
-            let mut s = format!("{}\n{}\n{}\n", r.code, r.desc, r.name);
+            rules += &format!("{}\n{}\n{}\n", r.code, r.desc, r.name);
             if !r.tags.is_empty() {
-                s += &format!("{}\n", r.tags.join(", "));
+                rules += &format!("{}\n", r.tags.join(", "));
             }
-            s += "\n";
-            file.write_all(s.as_bytes())?;
+            rules += "\n";
+        }
+        write_atomic(&self.config.rules_file, &rules)
+            .with_context(|| format!("Failed to write rules file: {}", self.config.rules_file.display()))?;
+
+        // Saved before the purchases, so it's never behind them
+        let next_id_file = self.config.next_id_file();
+        write_atomic(&next_id_file, &format!("{}\n", self.next_id))
+            .with_context(|| format!("Failed to write next purchase id: {}", next_id_file.display()))?;
+
+        // Purchases File
+        let (price_max, name_max, tags_max) = self.get_max_lengths();
+        let mut purchases = String::new();
+        for p in &self.purchases.0 {
+            // Write receipt description for UNKNOWN items so they're identifiable
+            let name = self.saved_name(p);
+
+            let cols = [
+                p.date.to_string(),
+                format!("{:>price_max$.2}", p.price),
+                format!("{:<name_max$}", name),
+                format!("{:<tags_max$}", p.tags.join(", ")),
+                p.store.clone().unwrap_or_default(),
+                p.receipt.clone().unwrap_or_default(),
+                p.flags(),
+                p.id.to_string(),
+            ];
+            purchases += &cols.join(" | ");
+            purchases += "\n";
         }
+        write_atomic(&self.config.purchases_file, &purchases)
+            .with_context(|| format!("Failed to write purchases file: {}", self.config.purchases_file.display()))?;
 
         Ok(())
     }
 }
 
+/// The id saved by the last write, or 0 before there was one
+fn read_next_id(path: &Path) -> Result<u64> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => text.trim().parse()
+            .with_context(|| format!("Invalid next purchase id in {}: {}", path.display(), text.trim())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e).with_context(|| format!("Failed to read next purchase id: {}", path.display())),
+    }
+}
+
+/// Write to a temporary file next to `path`, then rename it over `path`
+pub fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    let name = path.file_name().context("Path has no file name")?.to_string_lossy();
+    let tmp = path.with_file_name(format!(".{}.tmp", name));
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -414,6 +743,13 @@ mod tests {
         assert!(r.is_err());
     }
 
+    #[test]
+    fn test_receipt_type_from_hint() {
+        assert_eq!(ReceiptType::from_hint("2024-07-21-costco.jpg"), Some(ReceiptType::Costco));
+        assert_eq!(ReceiptType::from_hint("2024-07-21-Fred-Meyer.png"), Some(ReceiptType::FredMeyer));
+        assert_eq!(ReceiptType::from_hint("2024-07-21.jpg"), None);
+    }
+
     // Receipt::get_fields tests
     #[test]
     fn test_costco_normal_line() {
@@ -471,6 +807,23 @@ mod tests {
         assert_eq!(result, Some((9999, "EXPENSIVE ITEM".into(), 150.00)));
     }
 
+    #[test]
+    fn test_parse_line_skip_reason() {
+        let r = Receipt::new("costco wholesale".into()).unwrap();
+        assert_eq!(r.parse_line("99999999999999999999999 ITEM 5.99"), LineParse::Skipped("bad code '99999999999999999999999'".into()));
+        assert_eq!(r.parse_line("just some random text"), LineParse::NoMatch);
+    }
+
+    #[test]
+    fn test_printed_total() {
+        let r = Receipt::new("costco wholesale\n1234567 ORGANIC MILK 5.99\nSUBTOTAL 5.99\nTAX 0.00\n**** TOTAL 5.99".into()).unwrap();
+        assert_eq!(r.printed_total(), Some(5.99));
+        let r = Receipt::new("fred meyer\n12345 BREAD 3.49 F\nBALANCE 3.49".into()).unwrap();
+        assert_eq!(r.printed_total(), Some(3.49));
+        let r = Receipt::new("winco\nONION YLW CO 4093 1.29".into()).unwrap();
+        assert_eq!(r.printed_total(), None);
+    }
+
     // ItemMaps tests
     #[test]
     fn test_itemmaps_missing_file() {
@@ -567,17 +920,33 @@ mod tests {
         assert_eq!(purchases.len(), 1);
     }
 
+    #[test]
+    fn test_purchases_store_and_receipt_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("purchases");
+        std::fs::write(&path, "2024-07-21 | 5.99 | Onions | veggies | Costco | 0a1b2c3d4e5f\n2024-07-21 | 1.00 | Bread | bakery\n").unwrap();
+
+        let purchases = Purchases::init(&path).unwrap();
+        assert_eq!(purchases.len(), 2);
+        assert_eq!(purchases[0].store.as_deref(), Some("Costco"));
+        assert_eq!(purchases[0].receipt.as_deref(), Some("0a1b2c3d4e5f"));
+        assert_eq!(purchases[1].store, None);
+        assert_eq!(purchases[1].receipt, None);
+    }
+
+    #[test]
+    fn test_field_text_keeps_leading_zeros() {
+        let r = Receipt::new("winco".into()).unwrap();
+        assert_eq!(r.field_text("ONION YLW CO 004093 1,29"), Some(("004093", "1,29")));
+        let r = Receipt::new("costco wholesale".into()).unwrap();
+        assert_eq!(r.field_text("1234567 ORGANIC MILK 5.99"), Some(("1234567", "5.99")));
+    }
+
     // Round-trip test
     #[test]
     fn test_save_and_reload_purchases() {
         let dir = tempfile::tempdir().unwrap();
-        let config = Config {
-            image_dir: dir.path().join("images"),
-            upscaled_image_dir: dir.path().join("upscaled"),
-            done_file: dir.path().join("done"),
-            rules_file: dir.path().join("rules"),
-            purchases_file: dir.path().join("purchases"),
-        };
+        let config = Config::in_data_dir(dir.path());
 
         // Create empty rules and purchases files
         std::fs::write(&config.rules_file, "4093\nONION YLW CO\nOnions\nveggies\n").unwrap();
@@ -585,6 +954,7 @@ mod tests {
 
         let mut itemizer = FileItemizer::new(config).unwrap();
         itemizer.set_date(NaiveDate::from_ymd_opt(2024, 7, 21).unwrap());
+        itemizer.set_receipt("WinCo", "0a1b2c3d4e5f");
         itemizer.process_purchase(4093, "ONION YLW CO".into(), 5.99);
 
         itemizer.save_to_disk().unwrap();
@@ -594,6 +964,145 @@ mod tests {
         assert_eq!(purchases.len(), 1);
         assert_eq!(purchases[0].name, "Onions");
         assert_eq!(purchases[0].price, 5.99);
+        assert_eq!(purchases[0].store.as_deref(), Some("WinCo"));
+        assert_eq!(purchases[0].receipt.as_deref(), Some("0a1b2c3d4e5f"));
+        assert_eq!(purchases[0].confidence, None);
+    }
+
+    #[test]
+    fn test_add_manual() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::in_data_dir(dir.path());
+        std::fs::write(&config.rules_file, "4093\nONION YLW CO\nOnions\nveggies, produce\n").unwrap();
+        let mut itemizer = FileItemizer::new(config).unwrap();
+        let date = NaiveDate::from_ymd_opt(2024, 7, 21).unwrap();
+        let market = Some("Market".to_owned());
+
+        let p = itemizer.add_manual(ManualEntry { date, store: market.clone(), price: 6.5, name: Some("Strawberries".into()),
+            tags: Some(vec!["produce".into()]), ..ManualEntry::default() }).unwrap();
+        assert_eq!((p.name.as_str(), p.tags.clone(), p.manual), ("Strawberries", vec!["produce".to_owned()], true));
+        let p = itemizer.add_manual(ManualEntry { date, price: 1.0, desc: Some("ONION YLW CO".into()), ..ManualEntry::default() }).unwrap();
+        assert_eq!((p.name.as_str(), p.code), ("Onions", Some(4093)));
+        assert!(itemizer.add_manual(ManualEntry { date, price: 1.0, code: Some(1), ..ManualEntry::default() }).is_err());
+        assert!(itemizer.add_manual(ManualEntry { date, price: 1.0, ..ManualEntry::default() }).is_err());
+
+        itemizer.save_to_disk().unwrap();
+        let purchases = Purchases::init(&itemizer.config.purchases_file).unwrap();
+        assert_eq!(purchases.len(), 2);
+        assert!(purchases.iter().all(|p| p.manual));
+        assert_eq!(purchases[0].store, market);
+        // Rescanning a legacy receipt from the same day and store leaves them alone
+        assert_eq!(itemizer.remove_receipt("abc", date, "Market", true), 0);
+    }
+
+    #[test]
+    fn test_remove_receipt_legacy_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::in_data_dir(dir.path());
+        // Written before receipt ids existed: two stores on one day, one row with no store, and one
+        // from another day
+        std::fs::write(&config.purchases_file, "2024-07-21 | 1.29 | Onions | veggies | WinCo\n\
+            2024-07-21 | 5.99 | Milk | dairy | Costco\n2024-07-21 | 1.00 | Bread | bakery\n\
+            2024-07-22 | 1.00 | Bread | bakery\n").unwrap();
+        let mut itemizer = FileItemizer::new(config).unwrap();
+        let date = NaiveDate::from_ymd_opt(2024, 7, 21).unwrap();
+
+        assert_eq!(itemizer.remove_receipt("abc", date, "WinCo", false), 0);
+        assert_eq!(itemizer.remove_receipt("abc", date, "WinCo", true), 2);
+        let kept: Vec<(&str, NaiveDate)> = itemizer.purchases.iter().map(|p| (p.name.as_str(), p.date)).collect();
+        assert_eq!(kept, vec![("Milk", date), ("Bread", date.succ_opt().unwrap())]);
+    }
+
+    #[test]
+    fn test_rescan_ids_follow_items() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::in_data_dir(dir.path());
+        std::fs::write(&config.rules_file, "4093\nONION YLW CO\nOnions\nveggies\n\n4131\nAPPLE GALA\nApples\nproduce\n").unwrap();
+        std::fs::write(&config.purchases_file, "2024-07-21 | 3.99 | Onions | veggies | WinCo | abc | | 1\n\
+            2024-07-21 | 3.99 | Apples | produce | WinCo | abc | | 2\n").unwrap();
+        let mut itemizer = FileItemizer::new(config).unwrap();
+        let date = NaiveDate::from_ymd_opt(2024, 7, 21).unwrap();
+
+        // Same price, read in the other order
+        assert_eq!(itemizer.remove_receipt("abc", date, "WinCo", false), 2);
+        itemizer.set_receipt("WinCo", "abc");
+        assert_eq!(itemizer.process_purchase(4131, "APPLE GALA".into(), 3.99).id, 2);
+        assert_eq!(itemizer.process_purchase(4093, "ONION YLW CO".into(), 3.99).id, 1);
+        itemizer.clear_replaced();
+        assert_eq!(itemizer.purchases.len(), 2);
+    }
+
+    #[test]
+    fn test_rescan_ids_stay_with_their_receipt() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::in_data_dir(dir.path());
+        std::fs::write(&config.rules_file, "4093\nONION YLW CO\nOnions\nveggies\n").unwrap();
+        std::fs::write(&config.purchases_file, "2024-07-21 | 3.99 | Onions | veggies | WinCo | abc | | 1\n\
+            2024-07-21 | 3.99 | Onions | veggies | WinCo | abc | | 2\n").unwrap();
+        let mut itemizer = FileItemizer::new(config).unwrap();
+        let date = NaiveDate::from_ymd_opt(2024, 7, 21).unwrap();
+
+        // The first receipt is read again with one line fewer
+        itemizer.remove_receipt("abc", date, "WinCo", false);
+        itemizer.set_receipt("WinCo", "abc");
+        assert_eq!(itemizer.process_purchase(4093, "ONION YLW CO".into(), 3.99).id, 1);
+        itemizer.clear_replaced();
+
+        // The id of its missing line isn't given to the next receipt
+        itemizer.set_receipt("WinCo", "def");
+        assert_eq!(itemizer.process_purchase(4093, "ONION YLW CO".into(), 3.99).id, 3);
+    }
+
+    #[test]
+    fn test_purchase_ids_are_stable() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::in_data_dir(dir.path());
+        // Written before the id column existed
+        std::fs::write(&config.purchases_file, "2024-07-21 | 5.99 | Onions | veggies\n2024-07-21 | 1.00 | Bread | bakery\n").unwrap();
+
+        let mut itemizer = FileItemizer::new(config).unwrap();
+        assert_eq!(itemizer.purchases.iter().map(|p| p.id).collect::<Vec<_>>(), vec![1, 2]);
+        itemizer.purchases.delete(1).unwrap();
+        assert!(itemizer.purchases.delete(1).is_err());
+        itemizer.set_date(NaiveDate::from_ymd_opt(2024, 7, 22).unwrap());
+        assert_eq!(itemizer.process_purchase(4093, "ONION YLW CO".into(), 1.29).id, 3);
+        itemizer.save_to_disk().unwrap();
+
+        let purchases = Purchases::init(&itemizer.config.purchases_file).unwrap();
+        assert_eq!(purchases.iter().map(|p| p.id).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(purchases[0].name, "Bread");
+
+        // Deleting the newest purchase doesn't free its id, even after reloading
+        itemizer.purchases.delete(3).unwrap();
+        itemizer.save_to_disk().unwrap();
+        let mut itemizer = FileItemizer::new(itemizer.config).unwrap();
+        assert_eq!(itemizer.process_purchase(4093, "ONION YLW CO".into(), 1.29).id, 4);
+    }
+
+    #[test]
+    fn test_save_and_reload_confidence() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::in_data_dir(dir.path());
+        let mut itemizer = FileItemizer::new(config).unwrap();
+        itemizer.set_date(NaiveDate::from_ymd_opt(2024, 7, 21).unwrap());
+        itemizer.process_purchase(4093, "ONION YLW CO".into(), 5.99).confidence = Some(42);
+        itemizer.process_purchase(1326, "COCONUT STRIPS".into(), 3.00);
+        itemizer.save_to_disk().unwrap();
+
+        let purchases = Purchases::init(&itemizer.config.purchases_file).unwrap();
+        assert_eq!(purchases[0].confidence, Some(42));
+        assert_eq!(purchases[1].confidence, None);
+    }
+
+    #[test]
+    fn test_get_max_lengths_counts_tag_separators() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::in_data_dir(dir.path());
+        std::fs::write(&config.purchases_file, "2024-07-21 | 12.50 | Onions | veggies, produce\n\
+            2024-07-21 | 1.00 | Pop Tarts | snacks\n").unwrap();
+        let itemizer = FileItemizer::new(config).unwrap();
+        // "veggies, produce": two tags and one ", " between them
+        assert_eq!(itemizer.get_max_lengths(), (4, 9, 16));
     }
 
     // image_done tests
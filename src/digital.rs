@@ -0,0 +1,210 @@
+// © Zach Nielsen 2024
+
+use anyhow::{Context, Result, bail};
+use regex::Regex;
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// What kind of receipt a file in the image directory holds, from its extension
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceKind {
+    Image,
+    Pdf,
+    Html,
+}
+
+/// Resolution PDFs without embedded text are rendered at for OCR
+const RASTER_DPI: u32 = 300;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+impl SourceKind {
+    pub fn from_path(path: &Path) -> Self {
+        let ext = path.extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "pdf" => Self::Pdf,
+            "html" | "htm" => Self::Html,
+            _ => Self::Image,
+        }
+    }
+}
+
+/// Text embedded in a PDF, or `None` if it is only scanned images
+pub fn pdf_text(bytes: &[u8]) -> Result<Option<String>> {
+    let doc = lopdf::Document::load_mem(bytes).context("Failed to read PDF")?;
+    let pages: Vec<u32> = doc.get_pages().keys().cloned().collect();
+    let text = doc.extract_text(&pages).context("Failed to extract text from PDF")?;
+    if text.trim().is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(normalize(&text)))
+}
+
+/// Render every page of a PDF with `pdftoppm` and stack them into one PNG in `out_dir`, ready to
+/// be OCRed like a photo
+pub fn rasterize_pdf(pdf: &Path, out_dir: &Path) -> Result<PathBuf> {
+    let stem = pdf.file_stem().context("PDF path has no file name")?.to_string_lossy();
+    let prefix = out_dir.join(format!("{}-page", stem));
+    let status = Command::new("pdftoppm")
+        .args(["-r", &RASTER_DPI.to_string(), "-png"])
+        .arg(pdf)
+        .arg(&prefix)
+        .status()
+        .context("Failed to run pdftoppm; install poppler-utils to scan PDFs without embedded text")?;
+    if !status.success() {
+        bail!("pdftoppm failed on {}: {}", pdf.display(), status);
+    }
+
+    // pdftoppm names pages <prefix>-1.png, <prefix>-2.png, ... zero padded to the page count
+    let page_prefix = format!("{}-", prefix.file_name().unwrap_or_default().to_string_lossy());
+    let mut pages: Vec<PathBuf> = std::fs::read_dir(out_dir)
+        .with_context(|| format!("Failed to read directory: {}", out_dir.display()))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.file_name().is_some_and(|n| n.to_string_lossy().starts_with(&page_prefix)))
+        .collect();
+    pages.sort();
+    if pages.is_empty() {
+        bail!("pdftoppm rendered no pages from {}", pdf.display());
+    }
+
+    let images = pages.iter()
+        .map(|p| image::open(p).with_context(|| format!("Failed to open rendered page: {}", p.display())))
+        .collect::<Result<Vec<_>>>();
+    for p in &pages {
+        let _ = std::fs::remove_file(p);
+    }
+    let images = images?;
+
+    let width = images.iter().map(|i| i.width()).max().unwrap_or(0);
+    let height = images.iter().map(|i| i.height()).sum();
+    let mut stacked = image::RgbaImage::from_pixel(width, height, image::Rgba([255, 255, 255, 255]));
+    let mut y = 0;
+    for img in &images {
+        image::imageops::overlay(&mut stacked, &img.to_rgba8(), 0, y as i64);
+        y += img.height();
+    }
+
+    let out = out_dir.join(format!("{}.png", stem));
+    stacked.save(&out)
+        .with_context(|| format!("Failed to save rendered PDF: {}", out.display()))?;
+    Ok(out)
+}
+
+/// Visible text of a saved HTML e-receipt, one line per table row, paragraph or line break
+pub fn html_text(html: &str) -> String {
+    let hidden = Regex::new(r"(?is)<(script|style|head)\b.*?</(script|style|head)\s*>").unwrap();
+    let breaks = Regex::new(r"(?i)<br\s*/?>|</(tr|p|div|li|h[1-6]|table)\s*>").unwrap();
+    let cells = Regex::new(r"(?i)</t[dh]\s*>").unwrap();
+    let tags = Regex::new(r"<[^>]*>").unwrap();
+
+    let text = hidden.replace_all(html, "");
+    // Source line breaks are only formatting
+    let text = text.replace(['\r', '\n'], " ");
+    let text = breaks.replace_all(&text, "\n");
+    let text = cells.replace_all(&text, " ");
+    let text = tags.replace_all(&text, "");
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&#36;", "$")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&");
+    normalize(&text)
+}
+
+/// Collapse runs of whitespace and drop currency signs, so digital receipt lines look like OCRed
+/// ones to the store patterns
+fn normalize(text: &str) -> String {
+    let dollar = Regex::new(r"\$\s*(\d)").unwrap();
+    text.lines()
+        .map(|l| dollar.replace_all(&l.split_whitespace().collect::<Vec<_>>().join(" "), "$1").into_owned())
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_kind() {
+        assert_eq!(SourceKind::from_path(Path::new("2024-07-21-costco.PDF")), SourceKind::Pdf);
+        assert_eq!(SourceKind::from_path(Path::new("2024-07-21-fredmeyer.htm")), SourceKind::Html);
+        assert_eq!(SourceKind::from_path(Path::new("2024-07-21-winco.jpg")), SourceKind::Image);
+    }
+
+    #[test]
+    fn test_html_text() {
+        let html = "<html><head><title>Receipt</title><style>td { color: red }</style></head>\n\
+            <body><h1>Fred Meyer</h1>\n<table>\n\
+            <tr><td>1234567</td>\n<td>ORGANIC&nbsp;MILK</td><td>$5.99</td><td>F</td></tr>\n\
+            <tr><td>4093</td><td>ONION &amp; LEEK</td><td>&#36;1.29</td><td>F</td></tr>\n\
+            </table></body></html>";
+        assert_eq!(html_text(html), "Fred Meyer\n1234567 ORGANIC MILK 5.99 F\n4093 ONION & LEEK 1.29 F");
+    }
+
+    /// One page PDF with a line of text per entry
+    fn text_pdf(lines: &[&str]) -> Vec<u8> {
+        use lopdf::content::{Content, Operation};
+        use lopdf::{dictionary, Document, Object, Stream};
+
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Courier",
+        });
+        let resources_id = doc.add_object(dictionary! {
+            "Font" => dictionary! { "F1" => font_id },
+        });
+        let mut operations = vec![
+            Operation::new("BT", vec![]),
+            Operation::new("Tf", vec!["F1".into(), 12.into()]),
+            Operation::new("Td", vec![50.into(), 700.into()]),
+        ];
+        for line in lines {
+            operations.push(Operation::new("Tj", vec![Object::string_literal(*line)]));
+            operations.push(Operation::new("Td", vec![0.into(), (-14).into()]));
+        }
+        operations.push(Operation::new("ET", vec![]));
+        let content = Content { operations };
+        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content_id,
+        });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => vec![page_id.into()],
+            "Count" => 1,
+            "Resources" => resources_id,
+            "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+        }));
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+
+        let mut bytes = Vec::new();
+        doc.save_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_pdf_text() {
+        let text = pdf_text(&text_pdf(&["COSTCO WHOLESALE", "1234567 ORGANIC MILK $5.99"])).unwrap().unwrap();
+        assert!(text.contains("COSTCO WHOLESALE"), "{}", text);
+        assert!(text.contains("1234567 ORGANIC MILK 5.99"), "{}", text);
+        assert_eq!(pdf_text(&text_pdf(&[])).unwrap(), None);
+    }
+}
@@ -0,0 +1,295 @@
+// © Zach Nielsen 2024
+
+use crate::config::ExportConfig;
+use crate::data::{Purchase, Purchases, receipt_id};
+
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use regex::Regex;
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalFormat {
+    Ledger,
+    Hledger,
+    Beancount,
+}
+
+/// All purchases from one receipt
+pub struct Transaction<'a> {
+    pub id: String,
+    /// Ids the receipt was exported under before it had a receipt id, when its purchases were
+    /// grouped by date and store
+    pub legacy_ids: Vec<String>,
+    pub date: NaiveDate,
+    pub payee: String,
+    pub purchases: Vec<&'a Purchase>,
+}
+
+const ACCOUNT_WIDTH: usize = 44;
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Group purchases into one transaction per receipt, oldest first.
+///
+/// Purchases recorded before receipt ids existed are grouped by date and store, with an id hashed
+/// from those so it stays the same between exports. Manual purchases are a transaction each, with
+/// an id from the purchase id.
+pub fn transactions(purchases: &Purchases) -> Vec<Transaction<'_>> {
+    let mut groups: BTreeMap<(NaiveDate, String, String), Vec<&Purchase>> = BTreeMap::new();
+    for p in purchases.iter() {
+        let store = p.store.clone().unwrap_or_default();
+        let receipt = match &p.receipt {
+            Some(receipt) => receipt.clone(),
+            None if p.manual => format!("manual-{}", p.id),
+            None => String::new(),
+        };
+        groups.entry((p.date, store, receipt)).or_default().push(p);
+    }
+
+    groups.into_iter().map(|((date, store, receipt), purchases)| {
+        let (id, legacy_ids) = if receipt.is_empty() {
+            (legacy_id(date, &store), Vec::new())
+        } else if receipt.starts_with("manual-") {
+            (receipt, Vec::new())
+        } else {
+            // Rescanning replaces purchases saved with no receipt id, perhaps also with no store
+            (receipt, vec![legacy_id(date, &store), legacy_id(date, "")])
+        };
+        let payee = if store.is_empty() { "Groceries".to_owned() } else { store };
+        Transaction { id, legacy_ids, date, payee, purchases }
+    }).collect()
+}
+
+fn legacy_id(date: NaiveDate, store: &str) -> String {
+    format!("legacy-{}", receipt_id(format!("{}|{}", date, store).as_bytes()))
+}
+
+fn account_for<'a>(p: &Purchase, config: &'a ExportConfig) -> &'a str {
+    p.tags.iter()
+        .find_map(|t| config.accounts.get(t))
+        .unwrap_or(&config.default_account)
+}
+
+fn amount(v: f64, format: JournalFormat, config: &ExportConfig) -> String {
+    match format {
+        JournalFormat::Beancount => format!("{:.2} {}", v, config.beancount_currency),
+        JournalFormat::Ledger | JournalFormat::Hledger => {
+            if config.commodity.chars().all(|c| c.is_alphabetic()) {
+                format!("{:.2} {}", v, config.commodity)
+            } else {
+                format!("{}{:.2}", config.commodity, v)
+            }
+        }
+    }
+}
+
+/// Write one transaction. Postings are summed per account unless `per_item` is set.
+pub fn render(tx: &Transaction, format: JournalFormat, config: &ExportConfig, per_item: bool) -> String {
+    let mut s = match format {
+        JournalFormat::Beancount => format!("{} * \"{}\" \"Groceries\"\n  itemizer_id: \"{}\"\n",
+            tx.date, tx.payee.replace('"', "'"), tx.id),
+        JournalFormat::Ledger | JournalFormat::Hledger => format!("{} * ({}) {}\n    ; itemizer_id: {}\n",
+            tx.date, tx.id, tx.payee, tx.id),
+    };
+    let indent = if format == JournalFormat::Beancount { "  " } else { "    " };
+
+    if per_item {
+        for p in &tx.purchases {
+            let _ = writeln!(s, "{indent}{:<ACCOUNT_WIDTH$}  {:>12}  ; {}",
+                account_for(p, config), amount(p.price, format, config), p.name);
+        }
+    } else {
+        let mut postings: BTreeMap<&str, f64> = BTreeMap::new();
+        for p in &tx.purchases {
+            *postings.entry(account_for(p, config)).or_insert(0.0) += p.price;
+        }
+        for (account, total) in postings {
+            let _ = writeln!(s, "{indent}{:<ACCOUNT_WIDTH$}  {:>12}", account, amount(total, format, config));
+        }
+    }
+    let _ = writeln!(s, "{indent}{}", config.payment_account);
+    s
+}
+
+/// Ids of transactions already present in a journal written by an earlier export
+pub fn existing_ids(journal: &str) -> HashSet<String> {
+    let re = Regex::new(r#"itemizer_id: "?([\w-]+)"?"#).unwrap();
+    re.captures_iter(journal).map(|c| c[1].to_owned()).collect()
+}
+
+/// Print the journal, or append to `out`, skipping transactions it already contains. Like the
+/// totals, purchases tagged `EXCLUDE` are left out.
+pub fn export(purchases: &Purchases, format: JournalFormat, config: &ExportConfig, out: Option<&Path>, per_item: bool) -> Result<()> {
+    let txs: Vec<Transaction> = transactions(purchases).into_iter()
+        .filter_map(|mut tx| {
+            tx.purchases.retain(|p| !p.tags.iter().any(|t| t == "EXCLUDE"));
+            (!tx.purchases.is_empty()).then_some(tx)
+        })
+        .collect();
+
+    let Some(out) = out else {
+        for tx in &txs {
+            println!("{}", render(tx, format, config, per_item));
+        }
+        return Ok(());
+    };
+
+    let existing = match std::fs::read_to_string(out) {
+        Ok(text) => existing_ids(&text),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read journal: {}", out.display())),
+    };
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(out)
+        .with_context(|| format!("Failed to open journal for writing: {}", out.display()))?;
+
+    let mut added = 0;
+    let present = |tx: &Transaction| existing.contains(&tx.id) || tx.legacy_ids.iter().any(|id| existing.contains(id));
+    for tx in txs.iter().filter(|tx| !present(tx)) {
+        writeln!(file, "{}", render(tx, format, config, per_item))?;
+        added += 1;
+    }
+    println!("Exported {} new transactions to {} ({} already present)", added, out.display(), txs.len() - added);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn purchase(name: &str, tags: &str, price: f64, receipt: Option<&str>) -> Purchase {
+        Purchase {
+            name: name.to_owned(),
+            tags: crate::data::split_tags(tags),
+            price,
+            date: NaiveDate::from_ymd_opt(2024, 7, 21).unwrap(),
+            store: Some("Costco".to_owned()),
+            receipt: receipt.map(|r| r.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn config() -> ExportConfig {
+        let mut config = ExportConfig::default();
+        config.accounts.insert("produce".to_owned(), "Expenses:Groceries:Produce".to_owned());
+        config
+    }
+
+    #[test]
+    fn test_transactions_group_by_receipt() {
+        let purchases = Purchases(vec![
+            purchase("Onions", "veggies, produce", 1.29, Some("aaaa")),
+            purchase("Chips", "snacks", 3.00, Some("bbbb")),
+            purchase("Apples", "produce", 2.00, Some("aaaa")),
+        ]);
+        let txs = transactions(&purchases);
+        assert_eq!(txs.len(), 2);
+        assert_eq!(txs[0].id, "aaaa");
+        assert_eq!(txs[0].purchases.len(), 2);
+        assert_eq!(txs[0].payee, "Costco");
+    }
+
+    #[test]
+    fn test_legacy_ids_are_stable() {
+        let purchases = Purchases(vec![purchase("Onions", "produce", 1.29, None)]);
+        let a = transactions(&purchases)[0].id.clone();
+        let b = transactions(&purchases)[0].id.clone();
+        assert!(a.starts_with("legacy-"));
+        assert_eq!(a, b);
+
+        // Editing a legacy purchase or adding a manual one on the same day doesn't change it
+        let mut purchases = Purchases(vec![purchase("Yellow Onions", "produce", 1.30, None), purchase("Strawberries", "produce", 6.50, None)]);
+        purchases[1].id = 7;
+        purchases[1].manual = true;
+        let txs = transactions(&purchases);
+        assert_eq!(txs.len(), 2);
+        assert_eq!(txs[0].id, a);
+        assert_eq!(txs[1].id, "manual-7");
+    }
+
+    #[test]
+    fn test_render_ledger_sums_accounts() {
+        let purchases = Purchases(vec![
+            purchase("Onions", "veggies, produce", 1.29, Some("aaaa")),
+            purchase("Apples", "produce", 2.00, Some("aaaa")),
+            purchase("Chips", "snacks", 3.00, Some("aaaa")),
+        ]);
+        let txs = transactions(&purchases);
+        let s = render(&txs[0], JournalFormat::Ledger, &config(), false);
+        assert!(s.starts_with("2024-07-21 * (aaaa) Costco\n"));
+        assert!(s.contains("Expenses:Groceries:Produce"));
+        assert!(s.contains("$3.29"));
+        assert!(s.contains("$3.00"));
+        assert!(s.trim_end().ends_with("Liabilities:CreditCard"));
+        assert_eq!(existing_ids(&s), HashSet::from(["aaaa".to_owned()]));
+    }
+
+    #[test]
+    fn test_render_beancount() {
+        let purchases = Purchases(vec![purchase("Onions", "produce", 1.29, Some("aaaa"))]);
+        let txs = transactions(&purchases);
+        let s = render(&txs[0], JournalFormat::Beancount, &config(), true);
+        assert!(s.starts_with("2024-07-21 * \"Costco\" \"Groceries\"\n"));
+        assert!(s.contains("1.29 USD"));
+        assert!(s.contains("; Onions"));
+        assert_eq!(existing_ids(&s), HashSet::from(["aaaa".to_owned()]));
+    }
+
+    #[test]
+    fn test_export_skips_existing() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("groceries.journal");
+        let purchases = Purchases(vec![purchase("Onions", "produce", 1.29, Some("aaaa"))]);
+
+        export(&purchases, JournalFormat::Hledger, &config(), Some(&out), false).unwrap();
+        export(&purchases, JournalFormat::Hledger, &config(), Some(&out), false).unwrap();
+
+        let text = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(text.matches("itemizer_id: aaaa").count(), 1);
+    }
+
+    #[test]
+    fn test_export_after_rescan_adds_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("groceries.journal");
+        let mut purchases = Purchases(vec![purchase("Onions", "produce", 1.29, None), purchase("Apples", "produce", 2.00, None)]);
+        purchases[1].store = None;
+        export(&purchases, JournalFormat::Hledger, &config(), Some(&out), false).unwrap();
+
+        // Rescanning gives the purchases a receipt id, and the store where it was missing
+        for p in purchases.iter_mut() {
+            p.receipt = Some("aaaa".to_owned());
+            p.store = Some("Costco".to_owned());
+        }
+        export(&purchases, JournalFormat::Hledger, &config(), Some(&out), false).unwrap();
+
+        let text = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(text.matches("itemizer_id:").count(), 2);
+        assert!(!text.contains("itemizer_id: aaaa"));
+    }
+
+    #[test]
+    fn test_export_leaves_out_excluded() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("groceries.journal");
+        let purchases = Purchases(vec![
+            purchase("Onions", "produce", 1.29, Some("aaaa")),
+            purchase("MYSTERY ITEM", "EXCLUDE", 4.00, Some("aaaa")),
+            purchase("Gift card", "EXCLUDE", 25.00, Some("bbbb")),
+        ]);
+        export(&purchases, JournalFormat::Hledger, &config(), Some(&out), true).unwrap();
+
+        let text = std::fs::read_to_string(&out).unwrap();
+        assert!(!text.contains("MYSTERY ITEM"));
+        assert!(!text.contains("itemizer_id: bbbb"));
+        assert!(text.contains("$1.29"));
+    }
+}
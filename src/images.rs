@@ -0,0 +1,164 @@
+// © Zach Nielsen 2024
+
+use crate::config::Config;
+
+use anyhow::{Context, Result};
+use glob::Pattern;
+
+use std::path::{Path, PathBuf};
+
+/// Decides which files under the image directory are receipts to scan
+pub struct ImageFilter {
+    root: PathBuf,
+    extensions: Vec<String>,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    /// Itemizer's own directories, when they live inside the image directory
+    own_dirs: Vec<PathBuf>,
+}
+
+/// Files found under the image directory
+pub struct ImageFiles {
+    pub paths: Vec<PathBuf>,
+    /// Files that aren't receipts, or are filtered out by the config
+    pub skipped: usize,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+fn patterns(globs: &[String]) -> Result<Vec<Pattern>> {
+    globs.iter()
+        .map(|g| Pattern::new(g).with_context(|| format!("Invalid glob pattern in [images]: {}", g)))
+        .collect()
+}
+
+fn hidden(path: &Path) -> bool {
+    path.file_name().is_some_and(|n| n.to_string_lossy().starts_with('.'))
+}
+
+impl ImageFilter {
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            root: config.image_dir.clone(),
+            extensions: config.images.extensions.iter().map(|e| e.trim_start_matches('.').to_lowercase()).collect(),
+            include: patterns(&config.images.include)?,
+            exclude: patterns(&config.images.exclude)?,
+            own_dirs: [Some(config.upscaled_image_dir.clone()), Some(config.ocr_cache_dir()), config.archive.dir.clone()]
+                .into_iter().flatten().collect(),
+        })
+    }
+
+    pub fn accepts(&self, path: &Path) -> bool {
+        if hidden(path) || self.own_dirs.iter().any(|d| path.starts_with(d)) {
+            return false;
+        }
+        let ext = path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+        if !self.extensions.contains(&ext) {
+            return false;
+        }
+        let rel = path.strip_prefix(&self.root).unwrap_or(path);
+        if !self.include.is_empty() && !self.include.iter().any(|p| p.matches_path(rel)) {
+            return false;
+        }
+        !self.exclude.iter().any(|p| p.matches_path(rel))
+    }
+
+    /// Archived images are all receipts, and were already filtered on their way in
+    fn archive(config: &Config, dir: &Path) -> Self {
+        Self {
+            root: dir.to_owned(),
+            extensions: config.images.extensions.iter().map(|e| e.trim_start_matches('.').to_lowercase()).collect(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            own_dirs: Vec::new(),
+        }
+    }
+
+    fn enters(&self, dir: &Path) -> bool {
+        !hidden(dir) && !self.own_dirs.iter().any(|d| d == dir)
+    }
+}
+
+/// Receipt files in the image directory, and in its subdirectories when `recursive` is set.
+/// Hidden files and directories, like `.DS_Store` and `.stversions`, are never scanned.
+pub fn image_files(config: &Config) -> Result<ImageFiles> {
+    let filter = ImageFilter::new(config)?;
+    let mut files = ImageFiles { paths: Vec::new(), skipped: 0 };
+    walk(&config.image_dir, &filter, config.images.recursive, &mut files)?;
+    Ok(files)
+}
+
+/// Images already moved into the archive, for rescanning
+pub fn archived_files(config: &Config) -> Result<Vec<PathBuf>> {
+    let mut files = ImageFiles { paths: Vec::new(), skipped: 0 };
+    if let Some(dir) = config.archive.dir.as_ref().filter(|d| d.is_dir()) {
+        walk(dir, &ImageFilter::archive(config, dir), true, &mut files)?;
+    }
+    Ok(files.paths)
+}
+
+fn walk(dir: &Path, filter: &ImageFilter, recursive: bool, files: &mut ImageFiles) -> Result<()> {
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read image directory: {}", dir.display()))?;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let Ok(file_type) = entry.file_type() else { continue };
+        if file_type.is_dir() {
+            if recursive && filter.enters(&path) {
+                walk(&path, filter, recursive, files)?;
+            }
+        } else if file_type.is_file() {
+            if filter.accepts(&path) {
+                files.paths.push(path);
+            } else {
+                files.skipped += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_skips_notes_and_temp_files() {
+        let config = Config::in_data_dir(Path::new("/d"));
+        let filter = ImageFilter::new(&config).unwrap();
+        assert!(filter.accepts(Path::new("/d/images/2024-07-21-costco.JPG")));
+        assert!(filter.accepts(Path::new("/d/images/2024-07-21-costco.pdf")));
+        assert!(!filter.accepts(Path::new("/d/images/.DS_Store")));
+        assert!(!filter.accepts(Path::new("/d/images/notes.txt")));
+        assert!(!filter.accepts(Path::new("/d/images/.syncthing.2024-07-21-costco.jpg.tmp")));
+        assert!(!filter.accepts(Path::new("/d/images/~syncthing~2024-07-21-costco.jpg.tmp")));
+        assert!(!filter.accepts(Path::new("/d/images/2024-07-21-costco.pdf.crdownload")));
+    }
+
+    #[test]
+    fn test_image_files_recursive_with_globs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::in_data_dir(dir.path());
+        config.images.recursive = true;
+        config.images.exclude = vec!["old/**".to_owned()];
+        let images = &config.image_dir;
+        for d in ["2024/07", "old", ".stversions"] {
+            std::fs::create_dir_all(images.join(d)).unwrap();
+        }
+        for f in ["2024/07/2024-07-21-winco.png", "2024-07-22-costco.jpg", ".DS_Store", "notes.txt",
+                  "old/2023-01-01-costco.png", ".stversions/2024-07-21-winco.png"] {
+            std::fs::write(images.join(f), b"x").unwrap();
+        }
+
+        let mut files = image_files(&config).unwrap();
+        files.paths.sort();
+        assert_eq!(files.paths, vec![images.join("2024/07/2024-07-21-winco.png"), images.join("2024-07-22-costco.jpg")]);
+        assert_eq!(files.skipped, 3);
+
+        config.images.recursive = false;
+        config.images.include = vec!["*costco*".to_owned()];
+        let files = image_files(&config).unwrap();
+        assert_eq!(files.paths, vec![images.join("2024-07-22-costco.jpg")]);
+        assert_eq!(files.skipped, 2);
+    }
+}
@@ -0,0 +1,179 @@
+// © Zach Nielsen 2024
+
+use anyhow::{Context, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Writes progress to stderr at the chosen verbosity, and every record down to `debug` to an
+/// optional JSON lines file
+struct Logger {
+    terminal: LevelFilter,
+    file: Option<Mutex<File>>,
+}
+
+/// The receipt being worked on by this thread, attached to every record logged
+#[derive(Clone, Debug, Default)]
+struct ReceiptContext {
+    path: String,
+    store: Option<String>,
+}
+
+/// Clears this thread's receipt context when dropped
+pub struct ReceiptGuard;
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    time: String,
+    level: &'a str,
+    target: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receipt: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    store: Option<&'a str>,
+    message: String,
+}
+
+thread_local! {
+    static RECEIPT: RefCell<Option<ReceiptContext>> = const { RefCell::new(None) };
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Install the logger. `verbosity` is the number of `-v` flags less the number of `-q` flags.
+pub fn init(verbosity: i8, log_file: Option<&Path>) -> Result<()> {
+    let terminal = match verbosity {
+        i8::MIN..=-2 => LevelFilter::Error,
+        -1 => LevelFilter::Warn,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let file = match log_file {
+        Some(path) => Some(Mutex::new(OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("Failed to open log file: {}", path.display()))?)),
+        None => None,
+    };
+    let max = if file.is_some() { terminal.max(LevelFilter::Debug) } else { terminal };
+    log::set_boxed_logger(Box::new(Logger { terminal, file })).context("Logger already installed")?;
+    log::set_max_level(max);
+    Ok(())
+}
+
+/// Tag records logged on this thread with a receipt until the guard is dropped
+pub fn receipt(path: &str, store: Option<&str>) -> ReceiptGuard {
+    RECEIPT.with(|r| *r.borrow_mut() = Some(ReceiptContext { path: path.to_owned(), store: store.map(|s| s.to_owned()) }));
+    ReceiptGuard
+}
+
+/// Set the store of the current receipt once it has been read
+pub fn set_store(store: &str) {
+    RECEIPT.with(|r| {
+        if let Some(ctx) = r.borrow_mut().as_mut() {
+            ctx.store = Some(store.to_owned());
+        }
+    });
+}
+
+impl Drop for ReceiptGuard {
+    fn drop(&mut self) {
+        RECEIPT.with(|r| *r.borrow_mut() = None);
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Only our own records; dependencies log plenty at debug
+        metadata.target().starts_with(env!("CARGO_PKG_NAME"))
+            && (metadata.level() <= self.terminal || (self.file.is_some() && metadata.level() <= Level::Debug))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let ctx = RECEIPT.with(|r| r.borrow().clone());
+        if record.level() <= self.terminal {
+            eprintln!("{}", terminal_line(record, ctx.as_ref()));
+        }
+        if let Some(file) = &self.file {
+            if record.level() <= Level::Debug {
+                let line = json_line(record, ctx.as_ref(), chrono::Local::now().to_rfc3339());
+                if let Ok(mut file) = file.lock() {
+                    let _ = writeln!(file, "{}", line);
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+fn terminal_line(record: &Record, ctx: Option<&ReceiptContext>) -> String {
+    match record.level() {
+        Level::Error => format!("Error: {}", record.args()),
+        Level::Warn => format!("Warning: {}", record.args()),
+        Level::Info => record.args().to_string(),
+        // Workers interleave, so say which receipt detail lines are about
+        Level::Debug | Level::Trace => match ctx.and_then(|c| Path::new(&c.path).file_name()) {
+            Some(name) => format!("  [{}] {}", name.to_string_lossy(), record.args()),
+            None => format!("  {}", record.args()),
+        },
+    }
+}
+
+fn json_line(record: &Record, ctx: Option<&ReceiptContext>, time: String) -> String {
+    let json = JsonRecord {
+        time,
+        level: record.level().as_str(),
+        target: record.target(),
+        receipt: ctx.map(|c| c.path.as_str()),
+        store: ctx.and_then(|c| c.store.as_deref()),
+        message: record.args().to_string(),
+    };
+    serde_json::to_string(&json).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_formats() {
+        let ctx = ReceiptContext { path: "/i/2024-07-21-winco.png".to_owned(), store: Some("WinCo".to_owned()) };
+        let args = format_args!("Skipping line, bad price '1.2O'");
+        let record = Record::builder().args(args).level(Level::Debug).target("itemizer::data").build();
+
+        assert_eq!(terminal_line(&record, Some(&ctx)), "  [2024-07-21-winco.png] Skipping line, bad price '1.2O'");
+        let json: serde_json::Value = serde_json::from_str(&json_line(&record, Some(&ctx), "t".to_owned())).unwrap();
+        assert_eq!(json["level"], "DEBUG");
+        assert_eq!(json["receipt"], "/i/2024-07-21-winco.png");
+        assert_eq!(json["store"], "WinCo");
+        assert_eq!(json["message"], "Skipping line, bad price '1.2O'");
+
+        let record = Record::builder().args(format_args!("disk full")).level(Level::Warn).build();
+        assert_eq!(terminal_line(&record, None), "Warning: disk full");
+        assert!(!json_line(&record, None, "t".to_owned()).contains("receipt"));
+    }
+
+    #[test]
+    fn test_receipt_context_is_per_thread() {
+        let _guard = receipt("/i/a.png", None);
+        set_store("Costco");
+        std::thread::spawn(|| assert!(RECEIPT.with(|r| r.borrow().is_none()))).join().unwrap();
+        assert_eq!(RECEIPT.with(|r| r.borrow().clone()).unwrap().store.as_deref(), Some("Costco"));
+        drop(_guard);
+        assert!(RECEIPT.with(|r| r.borrow().is_none()));
+    }
+}
@@ -1,169 +1,365 @@
 // © Zach Nielsen 2024
 
+mod archive;
+mod bank;
+mod cache;
 mod config;
 mod data;
+mod digital;
+mod export;
+mod images;
+mod logging;
+mod ocr;
+mod output;
+mod preprocess;
+mod purchases;
+mod query;
+mod report;
+mod scan;
+mod sidecar;
+mod watch;
 
 use crate::config::Config;
 use crate::data::*;
+use crate::export::JournalFormat;
+use crate::ocr::TesseractOcr;
+use crate::output::{OutputFormat, Totals};
+use crate::purchases::{PurchaseEdit, PurchaseFilter};
+use crate::scan::ScanOptions;
 
-use anyhow::{Context, Result};
-use tesseract::Tesseract;
-use chrono::{NaiveDate, Local, Datelike, Months};
-use image::imageops::FilterType;
-use image::GenericImageView;
-use regex::Regex;
-use clap::{Parser, Subcommand};
+use anyhow::{Context, Result, bail};
+use chrono::{Local, Datelike, Months, NaiveDate};
+use clap::{Args, Parser, Subcommand};
 
 use std::collections::HashMap;
-use std::cmp::max;
-use std::fs::{DirEntry, OpenOptions};
-use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+    /// Show more detail, e.g. why lines were skipped; repeat for even more
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Only show warnings; repeat to only show errors
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    quiet: u8,
+    /// Also write detailed records, as JSON lines tagged with the receipt and store, to this file
+    #[arg(long, value_name = "PATH", global = true)]
+    log_file: Option<PathBuf>,
 }
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Scan receipt images and record purchases
-    Scan,
+    Scan {
+        /// Format of the totals summary printed after scanning
+        #[arg(short, long, value_enum, default_value_t)]
+        format: OutputFormat,
+        /// Set a tesseract variable for this scan, e.g. `--tess-var textord_heavy_nr=1`
+        #[arg(long = "tess-var", value_name = "KEY=VALUE", value_parser = ocr::parse_tess_var)]
+        tess_vars: Vec<(String, String)>,
+        /// Only use OCR text cached by earlier scans; images without any are skipped
+        #[arg(long)]
+        from_cache: bool,
+        /// Images to OCR at once. Defaults to the number of CPUs.
+        #[arg(short, long)]
+        jobs: Option<usize>,
+        /// Print the purchases and rules that would be added, without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Parse already scanned images again, replacing their purchases. Reuses cached OCR text, so
+    /// this is quick after changing rules.
+    Rescan {
+        #[arg(short, long, value_enum, default_value_t)]
+        format: OutputFormat,
+        /// Skip images with no cached OCR text instead of running OCR on them
+        #[arg(long)]
+        from_cache: bool,
+        /// Images to OCR at once. Defaults to the number of CPUs.
+        #[arg(short, long)]
+        jobs: Option<usize>,
+    },
+    /// Scan new receipts as they appear in the image directory
+    Watch {
+        /// Seconds a new file's size must stay the same before it is scanned
+        #[arg(long, default_value_t = 5)]
+        settle: u64,
+        /// Images to OCR at once. Defaults to the number of CPUs.
+        #[arg(short, long)]
+        jobs: Option<usize>,
+    },
     /// Display totals for a month
     Display {
         #[arg(short, long, default_value_t = 0)]
         offset: i8,
+        #[arg(short, long, value_enum, default_value_t)]
+        format: OutputFormat,
+        /// Draw bars next to each total and a 12 month sparkline per tag (text format only)
+        #[arg(short, long)]
+        chart: bool,
+    },
+    /// Write a self-contained report with charts of past spending
+    Report {
+        /// Path of the HTML file to write
+        #[arg(long)]
+        html: PathBuf,
+        /// Number of months to include, ending with the latest purchase
+        #[arg(short, long, default_value_t = 12)]
+        months: u32,
+    },
+    /// Write purchases as plain-text accounting transactions, one per receipt
+    Export {
+        #[command(flatten)]
+        journal: Journal,
+        /// Append to this journal instead of printing, skipping receipts it already contains
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+        /// One posting per item instead of one per account
+        #[arg(long)]
+        per_item: bool,
+    },
+    /// Match charges on a bank or credit card statement CSV to scanned receipts
+    Bank {
+        /// Statement exported from the bank, with a header row
+        statement: PathBuf,
+    },
+    /// Record a purchase that has no receipt to scan, e.g. cash at a farmers market
+    Add {
+        /// Date of the purchase, `YYYY-MM-DD`. Defaults to today.
+        #[arg(long)]
+        date: Option<NaiveDate>,
+        #[arg(long)]
+        store: Option<String>,
+        #[arg(long)]
+        price: f64,
+        /// Defaults to the name of the rule found by `--code` or `--desc`
+        #[arg(long)]
+        name: Option<String>,
+        /// Comma separated. Defaults to the tags of the rule found by `--code` or `--desc`.
+        #[arg(long)]
+        tags: Option<String>,
+        /// Item code to look up in the rules file
+        #[arg(long)]
+        code: Option<u64>,
+        /// Receipt description to look up in the rules file
+        #[arg(long)]
+        desc: Option<String>,
+    },
+    /// Answer ad-hoc questions, e.g. `itemizer query tag:snacks store:costco date>=2024-01 price>10`
+    Query {
+        /// Terms that must all match: `tag:`, `store:`, `name:`, `receipt:`, `is:manual`,
+        /// `is:flagged`, `date` and `price` with `:`, `<`, `<=`, `>` or `>=`. A leading `-`
+        /// or `!` negates a term; words alone search names. Options go before the terms.
+        #[arg(allow_hyphen_values = true)]
+        terms: Vec<String>,
+        /// Print count, sum and average per group instead of each purchase
+        #[arg(long, value_enum)]
+        by: Option<query::GroupBy>,
+        /// Include purchases tagged EXCLUDE
+        #[arg(long)]
+        all: bool,
+    },
+    /// List, correct or remove recorded purchases by id
+    Purchases {
+        #[command(subcommand)]
+        action: PurchasesCommand,
     },
     /// Initialize config with default values
     Init,
 }
 
+#[derive(Subcommand, Debug)]
+enum PurchasesCommand {
+    /// Show purchases with their ids
+    List {
+        /// First date to show, `YYYY-MM-DD`
+        #[arg(long)]
+        from: Option<NaiveDate>,
+        /// Last date to show, `YYYY-MM-DD`
+        #[arg(long)]
+        to: Option<NaiveDate>,
+        #[arg(long)]
+        store: Option<String>,
+        #[arg(long)]
+        tag: Option<String>,
+        /// Part of the name, case insensitive
+        #[arg(long)]
+        name: Option<String>,
+        /// Receipt id
+        #[arg(long)]
+        receipt: Option<String>,
+        /// Only purchases read with low OCR confidence
+        #[arg(long)]
+        low_confidence: bool,
+        /// Only purchases entered with `add`
+        #[arg(long)]
+        manual: bool,
+    },
+    /// Change fields of a purchase
+    Edit {
+        id: u64,
+        #[arg(long)]
+        date: Option<NaiveDate>,
+        #[arg(long)]
+        price: Option<f64>,
+        #[arg(long)]
+        name: Option<String>,
+        /// Comma separated
+        #[arg(long)]
+        tags: Option<String>,
+        #[arg(long)]
+        store: Option<String>,
+    },
+    /// Remove purchases
+    Delete {
+        #[arg(required = true)]
+        ids: Vec<u64>,
+    },
+}
+#[derive(Args, Debug)]
+#[group(required = true, multiple = false)]
+struct Journal {
+    #[arg(long)]
+    ledger: bool,
+    #[arg(long)]
+    hledger: bool,
+    #[arg(long)]
+    beancount: bool,
+}
+
+impl Journal {
+    fn format(&self) -> JournalFormat {
+        if self.beancount {
+            JournalFormat::Beancount
+        } else if self.hledger {
+            JournalFormat::Hledger
+        } else {
+            JournalFormat::Ledger
+        }
+    }
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    let verbosity = i16::from(cli.verbose) - i16::from(cli.quiet);
+    logging::init(verbosity.clamp(i8::MIN.into(), i8::MAX.into()) as i8, cli.log_file.as_deref())?;
 
-    match &cli.command {
-        Some(Commands::Init) => Config::init(),
-        Some(Commands::Display { offset }) => {
+    let command = cli.command.unwrap_or(Commands::Scan { format: OutputFormat::Text, tess_vars: Vec::new(), from_cache: false, jobs: None, dry_run: false });
+    match &command {
+        Commands::Init => Config::init(),
+        Commands::Display { offset, format, chart } => {
             let config = Config::load()?;
             let itemizer = FileItemizer::new(config)?;
-            display_month(&itemizer, offset)
+            display_month(&itemizer, offset, *format, *chart)
         }
-        Some(Commands::Scan) | None => {
+        Commands::Report { html, months } => {
             let config = Config::load()?;
             let itemizer = FileItemizer::new(config)?;
-            parse_files(itemizer)
+            report::write_html(itemizer.purchases(), *months, html)
         }
-    }
-}
-
-fn process_single_image(entry: &DirEntry, itemizer: &mut FileItemizer) -> Result<()> {
-    let entry_path = entry.path();
-    let entry_path_str = entry_path.to_str()
-        .context("Image path is not valid UTF-8")?;
-
-    if image_done(entry_path_str, &itemizer.config.done_file)? {
-        println!("Receipt already done, skipping: {}", entry_path_str);
-        return Ok(());
-    }
-
-    // Upscale image
-    let entry_name = entry.file_name().into_string()
-        .map_err(|_| anyhow::anyhow!("Filename is not valid UTF-8"))?;
-    let resized_path = resize_image(entry_path_str, &entry_name, &itemizer.config)?;
-
-    // OCR image
-    let tess = Tesseract::new(None, Some("eng"))
-        .map_err(|e| anyhow::anyhow!("Failed to initialize Tesseract: {}", e))?;
-    let mut tess = tess.set_image(&resized_path)
-        .map_err(|e| anyhow::anyhow!("Failed to set image for OCR: {}", e))?;
-    let text = tess.get_text()
-        .map_err(|e| anyhow::anyhow!("OCR failed: {}", e))?;
-
-    // Clean up upscaled image
-    if let Err(e) = std::fs::remove_file(&resized_path) {
-        eprintln!("Warning: could not clean up upscaled image {}: {}", resized_path, e);
-    }
-
-    // Get the date from the file name
-    let date_re = Regex::new(r"\d{4}-\d{2}-\d{2}").unwrap();
-    let file_name = entry.file_name();
-    let file_name_str = file_name.to_str()
-        .context("Filename is not valid UTF-8")?;
-    let date_str = date_re.find(file_name_str)
-        .with_context(|| format!("No date found in filename: {}", file_name_str))?
-        .as_str();
-    let date = NaiveDate::parse_from_str(date_str, "%Y-%m-%d")
-        .with_context(|| format!("Invalid date in filename: {}", date_str))?;
-    itemizer.set_date(date);
-
-    // Parse Receipt
-    let receipt = Receipt::new(text)?;
-    for line in receipt.text.lines() {
-        let Some((code, desc, price)) = receipt.get_fields(line) else {
-            continue;
-        };
-        itemizer.process_purchase(code, desc, price);
-    }
-
-    // Mark as done
-    let mut done_fp = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&itemizer.config.done_file)
-        .context("Failed to open done file for writing")?;
-    writeln!(done_fp, "{}", entry_path_str)?;
-
-    Ok(())
-}
-
-fn parse_files(mut itemizer: FileItemizer) -> Result<()> {
-    // Collect and sort entries by filename for deterministic date-ordered processing
-    let mut entries: Vec<DirEntry> = std::fs::read_dir(&itemizer.config.image_dir)
-        .with_context(|| format!("Failed to read image directory: {}", itemizer.config.image_dir.display()))?
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().map(|ft| ft.is_file()).unwrap_or(false))
-        .collect();
-    entries.sort_by_key(|e| e.file_name());
-
-    for entry in &entries {
-        if let Err(e) = process_single_image(entry, &mut itemizer) {
-            eprintln!("Error processing {:?}: {:?}", entry.path(), e);
-            continue;
+        Commands::Export { journal, out, per_item } => {
+            let config = Config::load()?;
+            let itemizer = FileItemizer::new(config)?;
+            export::export(itemizer.purchases(), journal.format(), &itemizer.config.export, out.as_deref(), *per_item)
+        }
+        Commands::Add { date, store, price, name, tags, code, desc } => {
+            let config = Config::load()?;
+            let mut itemizer = FileItemizer::new(config)?;
+            let entry = ManualEntry {
+                date: date.unwrap_or_else(|| Local::now().date_naive()),
+                store: store.clone(),
+                price: *price,
+                name: name.clone(),
+                tags: tags.as_deref().map(split_tags),
+                code: *code,
+                desc: desc.clone(),
+            };
+            let p = itemizer.add_manual(entry)?;
+            println!("Added: {} {:.2} {} [{}]", p.date, p.price, p.name, p.tags.join(", "));
+            itemizer.save_to_disk()
+        }
+        Commands::Query { terms, by, all } => {
+            let config = Config::load()?;
+            let itemizer = FileItemizer::new(config)?;
+            let query = query::Query::parse(terms)?;
+            query::run(itemizer.purchases(), &query, *by, *all);
+            Ok(())
+        }
+        Commands::Purchases { action } => {
+            let config = Config::load()?;
+            let mut itemizer = FileItemizer::new(config)?;
+            match action {
+                PurchasesCommand::List { from, to, store, tag, name, receipt, low_confidence, manual } => {
+                    let filter = PurchaseFilter {
+                        from: *from,
+                        to: *to,
+                        store: store.clone(),
+                        tag: tag.clone(),
+                        name: name.clone(),
+                        receipt: receipt.clone(),
+                        low_confidence: *low_confidence,
+                        manual: *manual,
+                    };
+                    purchases::list(itemizer.purchases(), &filter);
+                    Ok(())
+                }
+                PurchasesCommand::Edit { id, date, price, name, tags, store } => {
+                    let edit = PurchaseEdit { date: *date, price: *price, name: name.clone(), tags: tags.clone(), store: store.clone() };
+                    let p = itemizer.purchases.get_mut(*id)?;
+                    edit.apply(p)?;
+                    println!("Changed: {}", purchases::line(p));
+                    itemizer.save_to_disk()
+                }
+                PurchasesCommand::Delete { ids } => {
+                    for id in ids {
+                        let p = itemizer.purchases.delete(*id)?;
+                        println!("Deleted: {}", purchases::line(&p));
+                    }
+                    itemizer.save_to_disk()
+                }
+            }
+        }
+        Commands::Bank { statement } => {
+            let config = Config::load()?;
+            let itemizer = FileItemizer::new(config)?;
+            let charges = bank::read_statement(statement, &itemizer.config.bank)?;
+            let receipts = bank::receipt_totals(itemizer.purchases());
+            bank::print_matches(&bank::match_charges(charges, receipts, &itemizer.config.bank));
+            Ok(())
+        }
+        Commands::Scan { format, tess_vars, from_cache, jobs, dry_run } => {
+            let mut config = Config::load()?;
+            config.set_ocr_variables(tess_vars);
+            let itemizer = FileItemizer::new(config)?;
+            let options = ScanOptions { from_cache: *from_cache, jobs: jobs_or_cpus(*jobs), dry_run: *dry_run, ..ScanOptions::default() };
+            scan::parse_files(itemizer, &TesseractOcr, *format, options)
+        }
+        Commands::Watch { settle, jobs } => {
+            let config = Config::load()?;
+            let itemizer = FileItemizer::new(config)?;
+            let options = ScanOptions { jobs: jobs_or_cpus(*jobs), ..ScanOptions::default() };
+            watch::watch(itemizer, &TesseractOcr, options, Duration::from_secs(*settle))
+        }
+        Commands::Rescan { format, from_cache, jobs } => {
+            let config = Config::load()?;
+            let itemizer = FileItemizer::new(config)?;
+            let options = ScanOptions { from_cache: *from_cache, rescan: true, jobs: jobs_or_cpus(*jobs), ..ScanOptions::default() };
+            scan::parse_files(itemizer, &TesseractOcr, *format, options)
         }
     }
-
-    print_totals(itemizer.purchases());
-    itemizer.save_to_disk()?;
-    Ok(())
 }
 
-fn resize_image(path: &str, name: &str, config: &Config) -> Result<String> {
-    println!("About to open: {:?}", path);
-    let resized_path = config.upscaled_image_dir.join(name);
-    let resized_path_str = resized_path.to_str()
-        .context("Upscaled image path is not valid UTF-8")?
-        .to_owned();
-
-    if resized_path.exists() {
-        println!("Already upscaled [{}], moving on", resized_path_str);
-    } else {
-        let img = image::open(path)
-            .with_context(|| format!("Failed to open image: {}", path))?;
-        let (width, height) = img.dimensions();
-        println!("About to upscale: {:?}", path);
-        let upscale = 1.5;
-        let new_width = (width as f32 * upscale) as u32;
-        let new_height = (height as f32 * upscale) as u32;
-        let resized_img = image::imageops::resize(&img, new_width, new_height, FilterType::Lanczos3);
-        println!("About to save upscaled to: {}", &resized_path_str);
-        resized_img.save(&resized_path)
-            .with_context(|| format!("Failed to save upscaled image: {}", resized_path_str))?;
-        println!("Image has been upscaled and saved successfully.");
-    }
-    Ok(resized_path_str)
+fn jobs_or_cpus(jobs: Option<usize>) -> usize {
+    jobs.unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1))
 }
 
-fn display_month(itemizer: &FileItemizer, offset: &i8) -> Result<()> {
+fn display_month(itemizer: &FileItemizer, offset: &i8, format: OutputFormat, chart: bool) -> Result<()> {
+    if chart && format != OutputFormat::Text {
+        bail!("--chart only works with --format text");
+    }
     let now = Local::now().naive_local().date();
     let target = if *offset >= 0 {
         now.checked_add_months(Months::new(*offset as u32))
@@ -174,7 +370,9 @@ fn display_month(itemizer: &FileItemizer, offset: &i8) -> Result<()> {
     let target_year = target.year();
     let target_month = target.month();
 
-    println!("Showing: {} {}", target.format("%B"), target_year);
+    if format == OutputFormat::Text {
+        println!("Showing: {} {}", target.format("%B"), target_year);
+    }
 
     let mut keep_list = Purchases(Vec::new());
     for p in &itemizer.purchases().0 {
@@ -183,70 +381,18 @@ fn display_month(itemizer: &FileItemizer, offset: &i8) -> Result<()> {
         }
     }
 
-    print_totals(&keep_list);
-    Ok(())
-}
-
-
-fn print_totals(purchases: &Purchases) {
-    println!("\n===========================================================\n");
-    print_totals_by_name(purchases);
-    println!("\n===========================================================\n");
-    println!("\n===========================================================\n");
-    print_totals_by_tag(purchases);
-    println!("\n===========================================================\n");
-}
-
-pub fn print_totals_by_name(purchases: &Purchases) {
-    let mut total: f64 = 0.0;
-    let mut tot: HashMap<&str, f64> = HashMap::new();
-    for p in &purchases.0 {
-        if p.tags.contains(&"EXCLUDE".to_owned()) { continue; }
-        tot.entry(&p.name).and_modify(|val| *val += p.price).or_insert(p.price);
-        total += p.price;
-    }
-
-    let mut vec: Vec<(&str, f64)> = tot.iter().map(|(&k, &v)| (k, v)).collect();
-    vec.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-
-    let mut price_max = 10;
-    let mut name_max = 0;
-    for item in &vec {
-        price_max = max(price_max, item.1.to_string().len());
-        name_max = max(name_max, item.0.len());
-    }
-    println!("Totals by name: {:.2}", total);
-    for item in vec {
-        println!("{:>price_max$.2} | {:<name_max$}", item.1, item.0);
-    }
-}
-
-pub fn print_totals_by_tag(purchases: &Purchases) {
-    let mut total: f64 = 0.0;
-    let mut tot: HashMap<&str, f64> = HashMap::new();
-    for p in &purchases.0 {
-        if p.tags.is_empty() || p.tags.contains(&"EXCLUDE".to_owned()) {
-            continue;
-        }
-
-        for tag in &p.tags {
-            if tag.is_empty() { continue; }
-            tot.entry(tag).and_modify(|val| *val += p.price).or_insert(p.price);
-            total += p.price;
+    let totals = Totals::new(&target.format("%Y-%m").to_string(), &keep_list);
+    if chart {
+        let mut tag_history: HashMap<String, Vec<f64>> = HashMap::new();
+        let months = report::monthly_totals(itemizer.purchases(), target, 12);
+        for t in &totals.by_tag {
+            let series = months.iter()
+                .map(|m| m.totals.by_tag.iter().find(|mt| mt.tag == t.tag).map(|mt| mt.total).unwrap_or(0.0))
+                .collect();
+            tag_history.insert(t.tag.clone(), series);
         }
+        totals.print_chart(&tag_history);
+        return Ok(());
     }
-
-    let mut vec: Vec<(&str, f64)> = tot.iter().map(|(&k, &v)| (k, v)).collect();
-    vec.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-
-    let mut price_max = 10;
-    let mut name_max = 0;
-    for item in &vec {
-        price_max = max(price_max, item.1.to_string().len());
-        name_max = max(name_max, item.0.len());
-    }
-    println!("Totals by tag: {:.2}", total);
-    for item in vec {
-        println!("{:>price_max$.2} | {:<name_max$}", item.1, item.0);
-    }
+    totals.print(format)
 }
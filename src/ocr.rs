@@ -0,0 +1,270 @@
+// © Zach Nielsen 2024
+
+use crate::config::OcrConfig;
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use tesseract::{PageSegMode, Tesseract};
+
+use std::path::Path;
+
+/// Turns a preprocessed receipt image into text. Shared between scan workers.
+pub trait OcrEngine: Sync {
+    fn recognize(&self, image: &Path, settings: &OcrConfig) -> Result<OcrOutput>;
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OcrWord {
+    pub text: String,
+    /// 0-100
+    pub conf: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OcrOutput {
+    pub text: String,
+    /// Words of each line of `text`, when the engine reports confidences; empty otherwise
+    pub lines: Vec<Vec<OcrWord>>,
+}
+
+pub struct TesseractOcr;
+
+/// Returns text stored in fixtures instead of running OCR: `<dir>/<image name>.tsv` in tesseract's
+/// TSV format, or plain `<dir>/<image name>.txt`
+#[cfg(test)]
+pub struct FixtureOcr {
+    pub dir: std::path::PathBuf,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+pub fn page_seg_mode(psm: u8) -> Result<PageSegMode> {
+    Ok(match psm {
+        0 => PageSegMode::PsmOsdOnly,
+        1 => PageSegMode::PsmAutoOsd,
+        2 => PageSegMode::PsmAutoOnly,
+        3 => PageSegMode::PsmAuto,
+        4 => PageSegMode::PsmSingleColumn,
+        5 => PageSegMode::PsmSingleBlockVertText,
+        6 => PageSegMode::PsmSingleBlock,
+        7 => PageSegMode::PsmSingleLine,
+        8 => PageSegMode::PsmSingleWord,
+        9 => PageSegMode::PsmCircleWord,
+        10 => PageSegMode::PsmSingleChar,
+        11 => PageSegMode::PsmSparseText,
+        12 => PageSegMode::PsmSparseTextOsd,
+        13 => PageSegMode::PsmRawLine,
+        _ => bail!("Invalid page segmentation mode {} (expected 0-13)", psm),
+    })
+}
+
+impl OcrOutput {
+    pub fn from_text(text: String) -> Self {
+        Self { text, lines: Vec::new() }
+    }
+
+    /// Build from tesseract's TSV output. Words are joined with single spaces, one line of text
+    /// per OCR line, so line numbers in `text` index into `lines`.
+    pub fn from_tsv(tsv: &str) -> Self {
+        let mut lines: Vec<Vec<OcrWord>> = Vec::new();
+        let mut current = None;
+        for row in tsv.lines() {
+            // level page block par line word left top width height conf text
+            let cols: Vec<&str> = row.split('\t').collect();
+            if cols.len() < 12 || cols[0] != "5" {
+                continue;
+            }
+            let text = cols[11].trim();
+            if text.is_empty() { continue; }
+            let key = (cols[1], cols[2], cols[3], cols[4]);
+            if current != Some(key) {
+                current = Some(key);
+                lines.push(Vec::new());
+            }
+            let conf = cols[10].parse().unwrap_or(0.0);
+            lines.last_mut().unwrap().push(OcrWord { text: text.to_owned(), conf });
+        }
+
+        let text = lines.iter()
+            .map(|words| words.iter().map(|w| w.text.as_str()).collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>()
+            .join("\n");
+        Self { text, lines }
+    }
+
+    /// Append the text of a photo taken further down the same receipt, dropping the lines at its
+    /// start that repeat the end of this one. Returns how many lines were dropped.
+    pub fn append_overlapping(&mut self, next: OcrOutput) -> usize {
+        // OCR reads the same line slightly differently in each photo
+        let key = |l: &str| l.chars().filter(|c| c.is_alphanumeric()).collect::<String>().to_uppercase();
+        let ours: Vec<String> = self.text.lines().map(key).collect();
+        let theirs: Vec<String> = next.text.lines().map(key).collect();
+        let overlap = (1..=ours.len().min(theirs.len())).rev()
+            .find(|&k| ours[ours.len() - k..] == theirs[..k] && theirs[..k].iter().any(|l| !l.is_empty()))
+            .unwrap_or(0);
+
+        let aligned = self.lines.len() == ours.len() && next.lines.len() == theirs.len();
+        let text = self.text.lines()
+            .chain(next.text.lines().skip(overlap))
+            .collect::<Vec<_>>()
+            .join("\n");
+        self.text = text;
+        if aligned {
+            self.lines.extend(next.lines.into_iter().skip(overlap));
+        } else {
+            self.lines.clear();
+        }
+        overlap
+    }
+
+    /// Lowest confidence among the words of line `idx` that make up `fields`
+    pub fn field_confidence(&self, idx: usize, fields: &[&str]) -> Option<f32> {
+        let trim = |s: &str| s.trim_matches(|c: char| !c.is_ascii_alphanumeric() && c != '.' && c != ',').to_owned();
+        self.lines.get(idx)?
+            .iter()
+            .filter(|w| fields.contains(&trim(&w.text).as_str()))
+            .map(|w| w.conf)
+            .reduce(f32::min)
+    }
+}
+
+/// Parse a `key=value` pair for `--tess-var`
+pub fn parse_tess_var(s: &str) -> Result<(String, String)> {
+    let Some((key, value)) = s.split_once('=') else {
+        bail!("Expected key=value, got '{}'", s);
+    };
+    if key.trim().is_empty() {
+        bail!("Missing variable name in '{}'", s);
+    }
+    Ok((key.trim().to_owned(), value.to_owned()))
+}
+
+impl OcrEngine for TesseractOcr {
+    fn recognize(&self, image: &Path, settings: &OcrConfig) -> Result<OcrOutput> {
+        let image = image.to_str()
+            .ok_or_else(|| anyhow::anyhow!("Image path is not valid UTF-8"))?;
+        let mut tess = Tesseract::new(None, Some(&settings.language))
+            .map_err(|e| anyhow::anyhow!("Failed to initialize Tesseract: {}", e))?;
+
+        if let Some(whitelist) = &settings.whitelist {
+            tess = tess.set_variable("tessedit_char_whitelist", whitelist)
+                .map_err(|e| anyhow::anyhow!("Failed to set character whitelist: {}", e))?;
+        }
+        for (key, value) in &settings.variables {
+            tess = tess.set_variable(key, value)
+                .map_err(|e| anyhow::anyhow!("Failed to set tesseract variable {}={}: {}", key, value, e))?;
+        }
+        if let Some(psm) = settings.psm {
+            tess.set_page_seg_mode(page_seg_mode(psm)?);
+        }
+
+        let mut tess = tess.set_image(image)
+            .map_err(|e| anyhow::anyhow!("Failed to set image for OCR: {}", e))?;
+        // Must come after the image, which resets the resolution
+        if let Some(dpi) = settings.dpi {
+            tess = tess.set_source_resolution(dpi);
+        }
+        let mut tess = tess.recognize()
+            .map_err(|e| anyhow::anyhow!("OCR failed: {}", e))?;
+        let tsv = tess.get_tsv_text(0)
+            .map_err(|e| anyhow::anyhow!("OCR failed: {}", e))?;
+        let output = OcrOutput::from_tsv(&tsv);
+        if !output.lines.is_empty() {
+            return Ok(output);
+        }
+        tess.get_text()
+            .map(OcrOutput::from_text)
+            .map_err(|e| anyhow::anyhow!("OCR failed: {}", e))
+    }
+}
+
+#[cfg(test)]
+impl OcrEngine for FixtureOcr {
+    fn recognize(&self, image: &Path, _settings: &OcrConfig) -> Result<OcrOutput> {
+        use anyhow::Context;
+        let name = image.file_name()
+            .context("Image path has no file name")?
+            .to_string_lossy();
+        // TSV fixtures carry word confidences, like real tesseract output
+        let tsv = self.dir.join(format!("{}.tsv", name));
+        if let Ok(tsv) = std::fs::read_to_string(tsv) {
+            return Ok(OcrOutput::from_tsv(&tsv));
+        }
+        let fixture = self.dir.join(format!("{}.txt", name));
+        std::fs::read_to_string(&fixture)
+            .map(OcrOutput::from_text)
+            .with_context(|| format!("No OCR fixture for image: {}", fixture.display()))
+    }
+}
+
+/// Tesseract TSV rows for the given lines of `(word, confidence)`
+#[cfg(test)]
+pub fn fixture_tsv(lines: &[&[(&str, f32)]]) -> String {
+    let mut s = String::from("level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theight\tconf\ttext\n");
+    for (l, words) in lines.iter().enumerate() {
+        s += &format!("4\t1\t1\t1\t{}\t0\t0\t0\t0\t0\t-1\t\n", l + 1);
+        for (w, (text, conf)) in words.iter().enumerate() {
+            s += &format!("5\t1\t1\t1\t{}\t{}\t0\t0\t0\t0\t{}\t{}\n", l + 1, w + 1, conf, text);
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tess_var() {
+        assert_eq!(parse_tess_var("textord_heavy_nr=1").unwrap(), ("textord_heavy_nr".to_owned(), "1".to_owned()));
+        assert_eq!(parse_tess_var("a=b=c").unwrap(), ("a".to_owned(), "b=c".to_owned()));
+        assert!(parse_tess_var("novalue").is_err());
+        assert!(parse_tess_var("=1").is_err());
+    }
+
+    #[test]
+    fn test_from_tsv_builds_lines() {
+        let out = OcrOutput::from_tsv(&fixture_tsv(&[
+            &[("WinCo", 96.0), ("Foods", 95.0)],
+            &[("ONION", 91.0), ("YLW", 90.0), ("4093", 88.0), ("1.29", 41.5)],
+        ]));
+        assert_eq!(out.text, "WinCo Foods\nONION YLW 4093 1.29");
+        assert_eq!(out.lines[1][3], OcrWord { text: "1.29".to_owned(), conf: 41.5 });
+    }
+
+    #[test]
+    fn test_append_overlapping() {
+        let mut top = OcrOutput::from_text("COSTCO WHOLESALE\n1 MILK 5.99\n2 EGGS 4.99\n3 BREAD 3.49".into());
+        let bottom = OcrOutput::from_text("2 EGGS 4,99\n3 BREAD 3.49\n4 APPLES 6.99\nTOTAL 21.46".into());
+        assert_eq!(top.append_overlapping(bottom), 2);
+        assert_eq!(top.text, "COSTCO WHOLESALE\n1 MILK 5.99\n2 EGGS 4.99\n3 BREAD 3.49\n4 APPLES 6.99\nTOTAL 21.46");
+
+        let mut top = OcrOutput::from_tsv(&fixture_tsv(&[&[("1", 90.0), ("MILK", 90.0)], &[("2", 90.0), ("EGGS", 90.0)]]));
+        let bottom = OcrOutput::from_tsv(&fixture_tsv(&[&[("2", 50.0), ("EGGS", 50.0)], &[("3", 40.0), ("BREAD", 40.0)]]));
+        assert_eq!(top.append_overlapping(bottom), 1);
+        assert_eq!(top.lines.len(), 3);
+        assert_eq!(top.lines[2][0].conf, 40.0);
+
+        let mut top = OcrOutput::from_text("1 MILK 5.99".into());
+        assert_eq!(top.append_overlapping(OcrOutput::from_text("2 EGGS 4.99".into())), 0);
+        assert_eq!(top.text, "1 MILK 5.99\n2 EGGS 4.99");
+    }
+
+    #[test]
+    fn test_field_confidence() {
+        let out = OcrOutput::from_tsv(&fixture_tsv(&[
+            &[("ONION", 20.0), ("YLW", 90.0), ("4093", 88.0), ("$1.29", 41.5)],
+        ]));
+        // The low-confidence description word doesn't count
+        assert_eq!(out.field_confidence(0, &["4093", "1.29"]), Some(41.5));
+        assert_eq!(out.field_confidence(0, &["4093"]), Some(88.0));
+        assert_eq!(out.field_confidence(1, &["4093"]), None);
+        assert_eq!(OcrOutput::from_text("ONION 4093 1.29".into()).field_confidence(0, &["4093"]), None);
+    }
+
+    #[test]
+    fn test_page_seg_mode_range() {
+        assert_eq!(page_seg_mode(6).unwrap(), PageSegMode::PsmSingleBlock);
+        assert!(page_seg_mode(14).is_err());
+    }
+}
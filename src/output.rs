@@ -0,0 +1,266 @@
+// © Zach Nielsen 2024
+
+use crate::data::Purchases;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::cmp::max;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Padded tables for reading in a terminal
+    #[default]
+    Text,
+    Json,
+    Csv,
+    Tsv,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NameTotal {
+    pub name: String,
+    pub total: f64,
+}
+#[derive(Debug, Serialize)]
+pub struct TagTotal {
+    pub tag: String,
+    pub total: f64,
+}
+#[derive(Debug, Serialize)]
+pub struct Totals {
+    pub period: String,
+    pub total: f64,
+    pub by_name: Vec<NameTotal>,
+    pub by_tag: Vec<TagTotal>,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Round to whole cents so summed floats don't leak into structured output
+pub fn round_cents(v: f64) -> f64 {
+    (v * 100.0).round() / 100.0
+}
+
+fn sorted_totals(tot: HashMap<&str, f64>) -> Vec<(String, f64)> {
+    let mut vec: Vec<(String, f64)> = tot.into_iter().map(|(k, v)| (k.to_owned(), round_cents(v))).collect();
+    vec.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.0.cmp(&b.0)));
+    vec
+}
+
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+impl Totals {
+    pub fn new(period: &str, purchases: &Purchases) -> Self {
+        let mut total: f64 = 0.0;
+        let mut names: HashMap<&str, f64> = HashMap::new();
+        let mut tags: HashMap<&str, f64> = HashMap::new();
+        for p in &purchases.0 {
+            if p.tags.contains(&"EXCLUDE".to_owned()) { continue; }
+            names.entry(&p.name).and_modify(|val| *val += p.price).or_insert(p.price);
+            total += p.price;
+
+            for tag in &p.tags {
+                if tag.is_empty() { continue; }
+                tags.entry(tag).and_modify(|val| *val += p.price).or_insert(p.price);
+            }
+        }
+
+        Self {
+            period: period.to_owned(),
+            total: round_cents(total),
+            by_name: sorted_totals(names).into_iter().map(|(name, total)| NameTotal { name, total }).collect(),
+            by_tag: sorted_totals(tags).into_iter().map(|(tag, total)| TagTotal { tag, total }).collect(),
+        }
+    }
+
+    pub fn print(&self, format: OutputFormat) -> Result<()> {
+        match format {
+            OutputFormat::Text => self.print_text(),
+            OutputFormat::Json => {
+                let s = serde_json::to_string_pretty(self).context("Failed to serialize totals")?;
+                println!("{}", s);
+            }
+            OutputFormat::Csv => self.print_delimited(","),
+            OutputFormat::Tsv => self.print_delimited("\t"),
+        }
+        Ok(())
+    }
+
+    fn print_text(&self) {
+        self.print_tables(None);
+    }
+
+    /// Text output with a bar next to every row, plus a sparkline of each tag's monthly history
+    pub fn print_chart(&self, tag_history: &HashMap<String, Vec<f64>>) {
+        self.print_tables(Some(tag_history));
+    }
+
+    fn print_tables(&self, chart: Option<&HashMap<String, Vec<f64>>>) {
+        let by_name: Vec<(&str, f64)> = self.by_name.iter().map(|n| (n.name.as_str(), n.total)).collect();
+        let by_tag: Vec<(&str, f64)> = self.by_tag.iter().map(|t| (t.tag.as_str(), t.total)).collect();
+        let tag_total: f64 = by_tag.iter().map(|t| t.1).sum();
+        let no_history = HashMap::new();
+
+        println!("\n===========================================================\n");
+        print_table("Totals by name", self.total, &by_name, chart.map(|_| &no_history));
+        println!("\n===========================================================\n");
+        println!("\n===========================================================\n");
+        print_table("Totals by tag", tag_total, &by_tag, chart);
+        println!("\n===========================================================\n");
+    }
+
+    /// One row per total, so the output loads straight into a spreadsheet
+    fn print_delimited(&self, sep: &str) {
+        let field = |s: &str| if sep == "," { escape_csv(s) } else { s.replace(['\t', '\n'], " ") };
+        let period = field(&self.period);
+
+        println!("period{sep}section{sep}key{sep}total");
+        for n in &self.by_name {
+            println!("{period}{sep}name{sep}{}{sep}{:.2}", field(&n.name), n.total);
+        }
+        for t in &self.by_tag {
+            println!("{period}{sep}tag{sep}{}{sep}{:.2}", field(&t.tag), t.total);
+        }
+        println!("{period}{sep}total{sep}{sep}{:.2}", self.total);
+    }
+}
+
+/// Rows of `price | name`. With `chart`, each row also gets a bar scaled to the largest row and,
+/// when `chart` has an entry for the row, a sparkline of its history.
+fn print_table(title: &str, total: f64, rows: &[(&str, f64)], chart: Option<&HashMap<String, Vec<f64>>>) {
+    let mut price_max = 10;
+    let mut name_max = 0;
+    for item in rows {
+        price_max = max(price_max, item.1.to_string().len());
+        name_max = max(name_max, item.0.len());
+    }
+    println!("{}: {:.2}", title, total);
+
+    let Some(history) = chart else {
+        for item in rows {
+            println!("{:>price_max$.2} | {:<name_max$}", item.1, item.0);
+        }
+        return;
+    };
+    let row_max = rows.iter().map(|r| r.1).fold(0.0, f64::max);
+    for item in rows {
+        let bar = bar(item.1, row_max, BAR_WIDTH);
+        match history.get(item.0) {
+            Some(h) => println!("{:>price_max$.2} | {:<name_max$} | {:<BAR_WIDTH$} {}", item.1, item.0, bar, sparkline(h)),
+            None => println!("{:>price_max$.2} | {:<name_max$} | {}", item.1, item.0, bar),
+        }
+    }
+}
+
+const BAR_WIDTH: usize = 30;
+const BLOCKS: [char; 8] = ['▏', '▎', '▍', '▌', '▋', '▊', '▉', '█'];
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// A horizontal bar `width` cells long at `max`, drawn with eighth-cell precision
+pub fn bar(value: f64, max: f64, width: usize) -> String {
+    if max <= 0.0 || value <= 0.0 {
+        return String::new();
+    }
+    let eighths = ((value / max).min(1.0) * (width * 8) as f64).round() as usize;
+    let mut s = "█".repeat(eighths / 8);
+    let partial = eighths % 8;
+    if partial > 0 {
+        s.push(BLOCKS[partial - 1]);
+    }
+    // Never draw a non-zero row as empty
+    if s.is_empty() {
+        s.push(BLOCKS[0]);
+    }
+    s
+}
+
+/// One character per value, scaled between zero and the largest value
+pub fn sparkline(values: &[f64]) -> String {
+    let max = values.iter().cloned().fold(0.0, f64::max);
+    values.iter().map(|&v| {
+        if max <= 0.0 || v <= 0.0 {
+            ' '
+        } else {
+            SPARKS[((v / max) * (SPARKS.len() - 1) as f64).round() as usize]
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Purchase;
+    use chrono::NaiveDate;
+
+    fn purchase(name: &str, tags: &[&str], price: f64) -> Purchase {
+        Purchase {
+            name: name.to_owned(),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            price,
+            date: NaiveDate::from_ymd_opt(2024, 7, 21).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_totals_skip_excluded() {
+        let purchases = Purchases(vec![
+            purchase("Onions", &["veggies", "produce"], 1.29),
+            purchase("Onions", &["veggies", "produce"], 1.30),
+            purchase("Pop Tarts", &["EXCLUDE"], 4.00),
+            purchase("Bubbly Water", &[""], 5.99),
+        ]);
+        let totals = Totals::new("2024-07", &purchases);
+
+        assert_eq!(totals.total, 8.58);
+        assert_eq!(totals.by_name.len(), 2);
+        assert_eq!(totals.by_name[0].name, "Bubbly Water");
+        assert_eq!(totals.by_name[1].total, 2.59);
+        assert_eq!(totals.by_tag.len(), 2);
+        assert_eq!(totals.by_tag[0].tag, "produce");
+        assert_eq!(totals.by_tag[0].total, 2.59);
+    }
+
+    #[test]
+    fn test_bar_scaling() {
+        assert_eq!(bar(10.0, 10.0, 4), "████");
+        assert_eq!(bar(5.0, 10.0, 4), "██");
+        assert_eq!(bar(1.0, 10.0, 4), "▍");
+        assert_eq!(bar(0.001, 10.0, 4), "▏");
+        assert_eq!(bar(0.0, 10.0, 4), "");
+    }
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[0.0, 1.0, 7.0]), " ▂█");
+        assert_eq!(sparkline(&[0.0, 0.0]), "  ");
+    }
+
+    #[test]
+    fn test_escape_csv() {
+        assert_eq!(escape_csv("Onions"), "Onions");
+        assert_eq!(escape_csv("Chips, salted"), "\"Chips, salted\"");
+        assert_eq!(escape_csv("12\" Pizza"), "\"12\"\" Pizza\"");
+    }
+
+    #[test]
+    fn test_totals_json_shape() {
+        let purchases = Purchases(vec![purchase("Onions", &["produce"], 1.29)]);
+        let json = serde_json::to_value(Totals::new("all", &purchases)).unwrap();
+        assert_eq!(json["period"], "all");
+        assert_eq!(json["total"], 1.29);
+        assert_eq!(json["by_name"][0]["name"], "Onions");
+        assert_eq!(json["by_tag"][0]["tag"], "produce");
+    }
+}
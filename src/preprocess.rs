@@ -0,0 +1,489 @@
+// © Zach Nielsen 2024
+
+use crate::config::{PreprocessConfig, PreprocessStep};
+
+use anyhow::{Context, Result};
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, GrayImage, Luma, Rgba, RgbaImage};
+
+use std::path::Path;
+
+/// Geometry is estimated on a copy no larger than this, for speed
+const ANALYSIS_SIZE: u32 = 1000;
+/// Largest skew, in degrees, `deskew` searches for
+const MAX_SKEW: f64 = 15.0;
+
+/// Run a preprocessing chain on an image and save the result to `dir` as `name`, returning the
+/// path of the processed image
+pub fn preprocess_image(path: &str, dir: &Path, name: &str, pre: &PreprocessConfig) -> Result<String> {
+    log::debug!("About to open: {:?}", path);
+    let resized_path = dir.join(name);
+    let resized_path_str = resized_path.to_str()
+        .context("Upscaled image path is not valid UTF-8")?
+        .to_owned();
+
+    // Always regenerated; a leftover from an interrupted scan may have used other settings
+    let img = image::open(path)
+        .with_context(|| format!("Failed to open image: {}", path))?;
+    log::debug!("About to preprocess: {:?}", path);
+    let processed = apply(img, pre);
+    log::debug!("About to save upscaled to: {}", &resized_path_str);
+    processed.save(&resized_path)
+        .with_context(|| format!("Failed to save upscaled image: {}", resized_path_str))?;
+    log::debug!("Image has been upscaled and saved successfully.");
+    Ok(resized_path_str)
+}
+
+/// Crop and deskew, scale, then run each configured step in order
+pub fn apply(img: DynamicImage, pre: &PreprocessConfig) -> DynamicImage {
+    let mut img = img;
+    if pre.crop {
+        img = crop_to_paper(img);
+    }
+    if pre.deskew {
+        img = deskew(img);
+        // Rotating leaves background in the corners; trim it again
+        if pre.crop {
+            img = crop_to_paper(img);
+        }
+    }
+
+    let mut img = scale(img, pre.scale);
+    for step in &pre.steps {
+        img = match step {
+            PreprocessStep::Grayscale => img.grayscale(),
+            PreprocessStep::ContrastStretch => DynamicImage::ImageLuma8(contrast_stretch(&img.to_luma8())),
+            PreprocessStep::Threshold => DynamicImage::ImageLuma8(
+                adaptive_threshold(&img.to_luma8(), pre.threshold_radius, pre.threshold_offset)),
+            PreprocessStep::Denoise => DynamicImage::ImageLuma8(median3(&img.to_luma8())),
+            PreprocessStep::Sharpen => img.unsharpen(pre.sharpen_sigma, 1),
+        };
+    }
+    img
+}
+
+fn scale(img: DynamicImage, factor: f32) -> DynamicImage {
+    if (factor - 1.0).abs() < f32::EPSILON || factor <= 0.0 {
+        return img;
+    }
+    let (width, height) = img.dimensions();
+    let new_width = (width as f32 * factor) as u32;
+    let new_height = (height as f32 * factor) as u32;
+    DynamicImage::ImageRgba8(image::imageops::resize(&img, new_width, new_height, FilterType::Lanczos3))
+}
+
+/// Threshold that best separates the histogram into two classes (Otsu's method)
+pub fn otsu_threshold(img: &GrayImage) -> u8 {
+    let mut hist = [0u64; 256];
+    for p in img.pixels() {
+        hist[p[0] as usize] += 1;
+    }
+    let total = img.width() as f64 * img.height() as f64;
+    let sum_all: f64 = hist.iter().enumerate().map(|(i, &c)| i as f64 * c as f64).sum();
+
+    let (mut best, mut best_var) = (0u8, 0.0);
+    let (mut weight_bg, mut sum_bg) = (0.0, 0.0);
+    for (t, &count) in hist.iter().enumerate() {
+        weight_bg += count as f64;
+        if weight_bg == 0.0 { continue; }
+        let weight_fg = total - weight_bg;
+        if weight_fg == 0.0 { break; }
+        sum_bg += t as f64 * count as f64;
+        let mean_bg = sum_bg / weight_bg;
+        let mean_fg = (sum_all - sum_bg) / weight_fg;
+        let var = weight_bg * weight_fg * (mean_bg - mean_fg).powi(2);
+        if var > best_var {
+            best_var = var;
+            best = t as u8;
+        }
+    }
+    best
+}
+
+/// Small grayscale copy for estimating geometry, and the factor back to full size
+fn analysis_copy(img: &DynamicImage) -> (GrayImage, f64) {
+    let (w, h) = img.dimensions();
+    let factor = (w.max(h) as f64 / ANALYSIS_SIZE as f64).max(1.0);
+    if factor == 1.0 {
+        return (img.to_luma8(), 1.0);
+    }
+    let small = img.resize(ANALYSIS_SIZE, ANALYSIS_SIZE, FilterType::Triangle);
+    (small.to_luma8(), w as f64 / small.width() as f64)
+}
+
+/// Bounding box `(x, y, w, h)` of the bright paper against a darker background, if there is one
+pub fn find_paper(gray: &GrayImage) -> Option<(u32, u32, u32, u32)> {
+    let (w, h) = gray.dimensions();
+    let t = otsu_threshold(gray);
+    let mut rows = vec![0u32; h as usize];
+    let mut cols = vec![0u32; w as usize];
+    for (x, y, p) in gray.enumerate_pixels() {
+        if p[0] > t {
+            rows[y as usize] += 1;
+            cols[x as usize] += 1;
+        }
+    }
+
+    // Rows and columns crossing the paper are at least half as bright as the brightest one
+    let span = |counts: &[u32]| {
+        let max = *counts.iter().max()?;
+        if max == 0 { return None; }
+        let first = counts.iter().position(|&c| c * 2 >= max)?;
+        let last = counts.iter().rposition(|&c| c * 2 >= max)?;
+        Some((first as u32, last as u32))
+    };
+    let (x0, x1) = span(&cols)?;
+    let (y0, y1) = span(&rows)?;
+    let (bw, bh) = (x1 - x0 + 1, y1 - y0 + 1);
+
+    // Nothing to trim, or too small to be the receipt
+    let area = bw as f64 * bh as f64 / (w as f64 * h as f64);
+    if !(0.05..=0.95).contains(&area) {
+        return None;
+    }
+    Some((x0, y0, bw, bh))
+}
+
+pub fn crop_to_paper(img: DynamicImage) -> DynamicImage {
+    let (small, factor) = analysis_copy(&img);
+    let Some((x, y, w, h)) = find_paper(&small) else {
+        return img;
+    };
+
+    // A little margin so characters at the paper's edge survive
+    let (iw, ih) = img.dimensions();
+    let margin = (w.max(h) as f64 * factor * 0.01) as u32;
+    let x0 = ((x as f64 * factor) as u32).saturating_sub(margin);
+    let y0 = ((y as f64 * factor) as u32).saturating_sub(margin);
+    let x1 = (((x + w) as f64 * factor) as u32 + margin).min(iw);
+    let y1 = (((y + h) as f64 * factor) as u32 + margin).min(ih);
+    log::debug!("Cropping to paper: {}x{} at ({}, {})", x1 - x0, y1 - y0, x0, y0);
+    img.crop_imm(x0, y0, x1 - x0, y1 - y0)
+}
+
+/// Angle in degrees that text lines are rotated counter-clockwise from horizontal, in the same
+/// sense as `rotate`.
+///
+/// Tries each angle and keeps the one where ink piles up into the fewest, fullest rows.
+pub fn estimate_skew(gray: &GrayImage) -> f64 {
+    let ink = adaptive_threshold(gray, 15, 10);
+    let points: Vec<(f64, f64)> = ink.enumerate_pixels()
+        .filter(|(_, _, p)| p[0] == 0)
+        .map(|(x, y, _)| (x as f64, y as f64))
+        .collect();
+    if points.is_empty() {
+        return 0.0;
+    }
+
+    let diag = ((gray.width() as f64).powi(2) + (gray.height() as f64).powi(2)).sqrt();
+    let bins = diag as usize * 2 + 1;
+    let score = |deg: f64| {
+        let (sin, cos) = deg.to_radians().sin_cos();
+        let mut rows = vec![0u64; bins];
+        for &(x, y) in &points {
+            let r = y * cos - x * sin + diag;
+            rows[(r as usize).min(bins - 1)] += 1;
+        }
+        rows.iter().map(|&c| (c * c) as f64).sum::<f64>()
+    };
+
+    // Coarse pass, then refine around the best
+    let mut best = 0.0;
+    let mut best_score = score(0.0);
+    let mut step = 1.0;
+    let (mut lo, mut hi) = (-MAX_SKEW, MAX_SKEW);
+    while step >= 0.1 {
+        let mut deg = lo;
+        while deg <= hi + 1e-9 {
+            let s = score(deg);
+            if s > best_score {
+                best_score = s;
+                best = deg;
+            }
+            deg += step;
+        }
+        lo = best - step;
+        hi = best + step;
+        step /= 4.0;
+    }
+    // Image rows grow downwards, so the projection's angle is the opposite sense to `rotate`
+    -best
+}
+
+/// Rotate by `deg` degrees counter-clockwise about the centre, keeping the size and filling
+/// uncovered corners with `fill`
+pub fn rotate(img: &RgbaImage, deg: f64, fill: Rgba<u8>) -> RgbaImage {
+    let (w, h) = img.dimensions();
+    let (cx, cy) = (w as f64 / 2.0, h as f64 / 2.0);
+    let (sin, cos) = deg.to_radians().sin_cos();
+    RgbaImage::from_fn(w, h, |x, y| {
+        // Inverse map each output pixel back into the source and sample bilinearly
+        let (dx, dy) = (x as f64 + 0.5 - cx, y as f64 + 0.5 - cy);
+        let sx = dx * cos - dy * sin + cx - 0.5;
+        let sy = dx * sin + dy * cos + cy - 0.5;
+        if sx < 0.0 || sy < 0.0 || sx > (w - 1) as f64 || sy > (h - 1) as f64 {
+            return fill;
+        }
+        let (x0, y0) = (sx.floor() as u32, sy.floor() as u32);
+        let (x1, y1) = ((x0 + 1).min(w - 1), (y0 + 1).min(h - 1));
+        let (fx, fy) = (sx - x0 as f64, sy - y0 as f64);
+        let mut out = [0u8; 4];
+        for (c, o) in out.iter_mut().enumerate() {
+            let top = img.get_pixel(x0, y0)[c] as f64 * (1.0 - fx) + img.get_pixel(x1, y0)[c] as f64 * fx;
+            let bottom = img.get_pixel(x0, y1)[c] as f64 * (1.0 - fx) + img.get_pixel(x1, y1)[c] as f64 * fx;
+            *o = (top * (1.0 - fy) + bottom * fy).round() as u8;
+        }
+        Rgba(out)
+    })
+}
+
+/// Straighten text lines by rotating the image. Only rotation is corrected, not the perspective of
+/// a photo taken at an angle. Corners uncovered by the rotation get the border's average colour,
+/// so a following crop still sees them as background.
+pub fn deskew(img: DynamicImage) -> DynamicImage {
+    let (small, _) = analysis_copy(&img);
+    let skew = estimate_skew(&small);
+    if skew.abs() < 0.1 {
+        return img;
+    }
+    log::debug!("Deskewing by {:.1} degrees", skew);
+
+    let rgba = img.to_rgba8();
+    let (w, h) = rgba.dimensions();
+    let border: Vec<&Rgba<u8>> = rgba.enumerate_pixels()
+        .filter(|(x, y, _)| *x == 0 || *y == 0 || *x == w - 1 || *y == h - 1)
+        .map(|(_, _, p)| p)
+        .collect();
+    let mut fill = [0u8; 4];
+    for (c, f) in fill.iter_mut().enumerate() {
+        *f = (border.iter().map(|p| p[c] as u64).sum::<u64>() / border.len() as u64) as u8;
+    }
+    DynamicImage::ImageRgba8(rotate(&rgba, -skew, Rgba(fill)))
+}
+
+/// Map the 1st..99th percentile of brightness onto the full 0..255 range
+pub fn contrast_stretch(img: &GrayImage) -> GrayImage {
+    let mut hist = [0u64; 256];
+    for p in img.pixels() {
+        hist[p[0] as usize] += 1;
+    }
+    let count = img.width() as u64 * img.height() as u64;
+    let cut = count / 100;
+    let percentile = |from_top: bool| {
+        let mut seen = 0;
+        for i in 0..256 {
+            let v = if from_top { 255 - i } else { i };
+            seen += hist[v];
+            if seen > cut {
+                return v as f32;
+            }
+        }
+        if from_top { 255.0 } else { 0.0 }
+    };
+    let (lo, hi) = (percentile(false), percentile(true));
+    if hi <= lo {
+        return img.clone();
+    }
+
+    let mut out = img.clone();
+    for p in out.pixels_mut() {
+        let v = (p[0] as f32 - lo) * 255.0 / (hi - lo);
+        p[0] = v.clamp(0.0, 255.0) as u8;
+    }
+    out
+}
+
+/// Pixels darker than the mean of their `(2r+1)^2` neighbourhood by more than `offset` become
+/// black, everything else white
+pub fn adaptive_threshold(img: &GrayImage, radius: u32, offset: i32) -> GrayImage {
+    let (w, h) = img.dimensions();
+    let (w_us, h_us) = (w as usize, h as usize);
+
+    // Summed area table with an extra leading row and column of zeros
+    let mut integral = vec![0u64; (w_us + 1) * (h_us + 1)];
+    for y in 0..h_us {
+        let mut row = 0u64;
+        for x in 0..w_us {
+            row += img.get_pixel(x as u32, y as u32)[0] as u64;
+            integral[(y + 1) * (w_us + 1) + x + 1] = integral[y * (w_us + 1) + x + 1] + row;
+        }
+    }
+
+    let r = radius as usize;
+    let mut out = GrayImage::new(w, h);
+    for y in 0..h_us {
+        let (y0, y1) = (y.saturating_sub(r), (y + r + 1).min(h_us));
+        for x in 0..w_us {
+            let (x0, x1) = (x.saturating_sub(r), (x + r + 1).min(w_us));
+            let sum = integral[y1 * (w_us + 1) + x1] + integral[y0 * (w_us + 1) + x0]
+                - integral[y0 * (w_us + 1) + x1] - integral[y1 * (w_us + 1) + x0];
+            let mean = sum as f64 / ((x1 - x0) * (y1 - y0)) as f64;
+            let v = img.get_pixel(x as u32, y as u32)[0] as f64;
+            let ink = v < mean - offset as f64;
+            out.put_pixel(x as u32, y as u32, Luma([if ink { 0 } else { 255 }]));
+        }
+    }
+    out
+}
+
+/// 3x3 median filter; removes speckle while keeping character edges
+pub fn median3(img: &GrayImage) -> GrayImage {
+    let (w, h) = img.dimensions();
+    let mut out = img.clone();
+    for y in 0..h {
+        for x in 0..w {
+            let mut window = [0u8; 9];
+            let mut n = 0;
+            for dy in -1i64..=1 {
+                for dx in -1i64..=1 {
+                    let nx = (x as i64 + dx).clamp(0, w as i64 - 1) as u32;
+                    let ny = (y as i64 + dy).clamp(0, h as i64 - 1) as u32;
+                    window[n] = img.get_pixel(nx, ny)[0];
+                    n += 1;
+                }
+            }
+            window.sort_unstable();
+            out.put_pixel(x, y, Luma([window[4]]));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(w: u32, h: u32) -> GrayImage {
+        GrayImage::from_fn(w, h, |x, _| Luma([(100 + x * 50 / w) as u8]))
+    }
+
+    #[test]
+    fn test_scale_default_upscales() {
+        let img = DynamicImage::ImageLuma8(gradient(20, 10));
+        let out = apply(img, &PreprocessConfig::default());
+        assert_eq!(out.dimensions(), (30, 15));
+    }
+
+    #[test]
+    fn test_contrast_stretch_fills_range() {
+        let out = contrast_stretch(&gradient(100, 4));
+        let min = out.pixels().map(|p| p[0]).min().unwrap();
+        let max = out.pixels().map(|p| p[0]).max().unwrap();
+        assert_eq!(min, 0);
+        assert_eq!(max, 255);
+    }
+
+    #[test]
+    fn test_threshold_finds_text_under_uneven_light() {
+        // Background brightens left to right; a dark "stroke" sits in the bright half
+        let mut img = gradient(60, 20);
+        for y in 8..12 {
+            for x in 40..44 {
+                img.put_pixel(x, y, Luma([110]));
+            }
+        }
+        let out = adaptive_threshold(&img, 5, 10);
+        assert!(out.pixels().all(|p| p[0] == 0 || p[0] == 255));
+        assert_eq!(out.get_pixel(41, 10)[0], 0);
+        assert_eq!(out.get_pixel(5, 10)[0], 255);
+        assert_eq!(out.get_pixel(55, 2)[0], 255);
+    }
+
+    #[test]
+    fn test_median_removes_speckle() {
+        let mut img = GrayImage::from_pixel(9, 9, Luma([255]));
+        img.put_pixel(4, 4, Luma([0]));
+        let out = median3(&img);
+        assert_eq!(out.get_pixel(4, 4)[0], 255);
+    }
+
+    /// White "receipt" with dark text lines, on a dark counter
+    fn receipt_on_counter(skew_deg: f64) -> RgbaImage {
+        let mut img = RgbaImage::from_pixel(300, 400, Rgba([40, 40, 40, 255]));
+        for y in 80..320 {
+            for x in 100..200 {
+                img.put_pixel(x, y, Rgba([240, 240, 240, 255]));
+            }
+        }
+        for line in 0..10 {
+            let y = 100 + line * 20;
+            for x in 110..190 {
+                for t in 0..3 {
+                    img.put_pixel(x, y + t, Rgba([20, 20, 20, 255]));
+                }
+            }
+        }
+        if skew_deg == 0.0 { img } else { rotate(&img, skew_deg, Rgba([40, 40, 40, 255])) }
+    }
+
+    #[test]
+    fn test_otsu_splits_bimodal() {
+        let img = GrayImage::from_fn(10, 10, |x, _| Luma([if x < 5 { 30 } else { 220 }]));
+        let t = otsu_threshold(&img);
+        assert!((30..220).contains(&t));
+    }
+
+    #[test]
+    fn test_find_paper() {
+        let img = DynamicImage::ImageRgba8(receipt_on_counter(0.0)).to_luma8();
+        let (x, y, w, h) = find_paper(&img).unwrap();
+        assert!((98..=102).contains(&x), "x = {}", x);
+        assert!((78..=82).contains(&y), "y = {}", y);
+        assert!((98..=102).contains(&w), "w = {}", w);
+        assert!((238..=242).contains(&h), "h = {}", h);
+    }
+
+    #[test]
+    fn test_find_paper_whole_image_is_paper() {
+        let img = GrayImage::from_pixel(50, 50, Luma([240]));
+        assert_eq!(find_paper(&img), None);
+    }
+
+    #[test]
+    fn test_estimate_skew() {
+        let img = DynamicImage::ImageRgba8(receipt_on_counter(4.0)).to_luma8();
+        let skew = estimate_skew(&img);
+        assert!((skew - 4.0).abs() < 0.5, "skew = {}", skew);
+
+        let straight = DynamicImage::ImageRgba8(receipt_on_counter(0.0)).to_luma8();
+        assert!(estimate_skew(&straight).abs() < 0.2);
+    }
+
+    #[test]
+    fn test_preprocess_replaces_leftover_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let upscaled = dir.path().join("upscaled");
+        std::fs::create_dir_all(&upscaled).unwrap();
+        let src = dir.path().join("2024-07-21-winco.png");
+        gradient(20, 10).save(&src).unwrap();
+        // Left by an earlier scan at another scale
+        gradient(4, 2).save(upscaled.join("2024-07-21-winco.png")).unwrap();
+
+        let out = preprocess_image(src.to_str().unwrap(), &upscaled, "2024-07-21-winco.png", &PreprocessConfig::default()).unwrap();
+        assert_eq!(image::open(out).unwrap().dimensions(), (30, 15));
+    }
+
+    #[test]
+    fn test_deskew_then_crop() {
+        let pre = PreprocessConfig { crop: true, deskew: true, scale: 1.0, ..PreprocessConfig::default() };
+        let out = apply(DynamicImage::ImageRgba8(receipt_on_counter(4.0)), &pre);
+        let (w, h) = out.dimensions();
+        assert!((100..110).contains(&w) && (240..250).contains(&h), "{}x{}", w, h);
+
+        let straightened = deskew(DynamicImage::ImageRgba8(receipt_on_counter(4.0)));
+        assert!(estimate_skew(&straightened.to_luma8()).abs() < 0.2);
+    }
+
+    #[test]
+    fn test_steps_run_in_order() {
+        let pre = PreprocessConfig {
+            scale: 1.0,
+            steps: vec![PreprocessStep::Grayscale, PreprocessStep::Threshold],
+            ..PreprocessConfig::default()
+        };
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(8, 8, image::Rgb([200, 10, 10])));
+        let out = apply(img, &pre);
+        assert!(matches!(out, DynamicImage::ImageLuma8(_)));
+        assert_eq!(out.dimensions(), (8, 8));
+    }
+}
@@ -0,0 +1,129 @@
+// © Zach Nielsen 2024
+
+use crate::data::{Purchase, Purchases, split_tags};
+
+use anyhow::{Result, bail};
+use chrono::NaiveDate;
+
+/// Which purchases `purchases list` shows; unset fields match everything
+#[derive(Clone, Debug, Default)]
+pub struct PurchaseFilter {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    /// Case insensitive
+    pub store: Option<String>,
+    pub tag: Option<String>,
+    /// Case insensitive substring of the name
+    pub name: Option<String>,
+    pub receipt: Option<String>,
+    /// Only purchases flagged for low OCR confidence
+    pub low_confidence: bool,
+    pub manual: bool,
+}
+
+/// Fields replaced by `purchases edit`
+#[derive(Clone, Debug, Default)]
+pub struct PurchaseEdit {
+    pub date: Option<NaiveDate>,
+    pub price: Option<f64>,
+    pub name: Option<String>,
+    /// Comma separated
+    pub tags: Option<String>,
+    pub store: Option<String>,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+impl PurchaseFilter {
+    pub fn matches(&self, p: &Purchase) -> bool {
+        let lower = |s: &str| s.to_lowercase();
+        self.from.is_none_or(|d| p.date >= d)
+            && self.to.is_none_or(|d| p.date <= d)
+            && self.store.as_ref().is_none_or(|s| p.store.as_deref().is_some_and(|ps| lower(ps) == lower(s)))
+            && self.tag.as_ref().is_none_or(|t| p.tags.contains(t))
+            && self.name.as_ref().is_none_or(|n| lower(&p.name).contains(&lower(n)))
+            && self.receipt.as_ref().is_none_or(|r| p.receipt.as_ref() == Some(r))
+            && (!self.low_confidence || p.confidence.is_some())
+            && (!self.manual || p.manual)
+    }
+}
+
+impl PurchaseEdit {
+    /// Change the purchase and mark it edited. A corrected price is no longer in doubt, so its low
+    /// confidence flag is cleared.
+    pub fn apply(&self, p: &mut Purchase) -> Result<()> {
+        if self.date.is_none() && self.price.is_none() && self.name.is_none() && self.tags.is_none() && self.store.is_none() {
+            bail!("Nothing to change; give at least one of --date, --price, --name, --tags or --store");
+        }
+        if let Some(date) = self.date { p.date = date; }
+        if let Some(price) = self.price {
+            p.price = price;
+            p.confidence = None;
+        }
+        if let Some(name) = &self.name { p.name = name.clone(); }
+        if let Some(tags) = &self.tags { p.tags = split_tags(tags); }
+        if let Some(store) = &self.store {
+            p.store = Some(store.clone()).filter(|s| !s.is_empty());
+        }
+        p.edited = true;
+        Ok(())
+    }
+}
+
+/// Print matching purchases, one per line, oldest first
+pub fn list(purchases: &Purchases, filter: &PurchaseFilter) {
+    let mut matching: Vec<&Purchase> = purchases.iter().filter(|p| filter.matches(p)).collect();
+    matching.sort_by_key(|p| (p.date, p.id));
+    let total: f64 = matching.iter().map(|p| p.price).sum();
+    for p in &matching {
+        println!("{}", line(p));
+    }
+    println!("{} purchases, {:.2}", matching.len(), total);
+}
+
+pub fn line(p: &Purchase) -> String {
+    let s = format!("{:>6}  {}  {:>8.2}  {:<30}  {:<24}  {:<12}  {:<12}  {}",
+        p.id, p.date, p.price, p.name, p.tags.join(", "), p.store.as_deref().unwrap_or(""),
+        p.receipt.as_deref().unwrap_or(""), p.flags());
+    s.trim_end().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn purchase(id: u64, name: &str, store: Option<&str>, date: &str) -> Purchase {
+        Purchase {
+            id,
+            name: name.to_owned(),
+            tags: vec!["produce".to_owned()],
+            price: 1.0,
+            date: date.parse().unwrap(),
+            store: store.map(|s| s.to_owned()),
+            confidence: Some(40),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let p = purchase(1, "Yellow Onions", Some("WinCo"), "2024-07-21");
+        assert!(PurchaseFilter::default().matches(&p));
+        assert!(PurchaseFilter { store: Some("winco".into()), name: Some("onion".into()), ..Default::default() }.matches(&p));
+        assert!(PurchaseFilter { from: "2024-07-01".parse().ok(), to: "2024-07-31".parse().ok(), ..Default::default() }.matches(&p));
+        assert!(!PurchaseFilter { from: "2024-08-01".parse().ok(), ..Default::default() }.matches(&p));
+        assert!(!PurchaseFilter { tag: Some("snacks".into()), ..Default::default() }.matches(&p));
+        assert!(!PurchaseFilter { manual: true, ..Default::default() }.matches(&p));
+        assert!(!PurchaseFilter { store: Some("Costco".into()), ..Default::default() }.matches(&purchase(2, "Milk", None, "2024-07-21")));
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut p = purchase(1, "Onions", Some("WinCo"), "2024-07-21");
+        PurchaseEdit { price: Some(1.29), tags: Some("veggies, produce".into()), ..Default::default() }.apply(&mut p).unwrap();
+        assert_eq!((p.price, p.confidence), (1.29, None));
+        assert_eq!(p.tags, vec!["veggies", "produce"]);
+        assert!(p.edited);
+        assert!(PurchaseEdit::default().apply(&mut p).is_err());
+    }
+}
@@ -0,0 +1,323 @@
+// © Zach Nielsen 2024
+
+use crate::data::{Purchase, Purchases};
+use crate::output::round_cents;
+use crate::purchases;
+
+use anyhow::{Context, Result, bail};
+use chrono::{Datelike, NaiveDate};
+use clap::ValueEnum;
+use regex::Regex;
+
+use std::collections::BTreeMap;
+
+/// Purchases matching every term of a query like `tag:snacks store:costco date>=2024-01 price>10`
+#[derive(Debug)]
+pub struct Query {
+    terms: Vec<Term>,
+}
+
+/// One condition, negated with a leading `-` or `!`
+#[derive(Debug, PartialEq)]
+struct Term {
+    negate: bool,
+    cond: Condition,
+}
+
+#[derive(Debug, PartialEq)]
+enum Condition {
+    Tag(String),
+    /// Case insensitive
+    Store(String),
+    /// Case insensitive substring
+    Name(String),
+    Receipt(String),
+    /// First and last day of a year, month or day
+    Date(Cmp, NaiveDate, NaiveDate),
+    Price(Cmp, f64),
+    Manual,
+    LowConfidence,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Cmp {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum GroupBy {
+    Name,
+    /// A purchase counts towards each of its tags
+    Tag,
+    Store,
+    /// ISO week, e.g. `2024-W29`
+    Week,
+    Month,
+}
+
+/// Count, sum and average of one group
+#[derive(Debug, Default, PartialEq)]
+pub struct Aggregate {
+    pub count: usize,
+    pub sum: f64,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+impl Query {
+    /// Parse one term per argument; words with no field search the name
+    pub fn parse(args: &[String]) -> Result<Self> {
+        // Terms may start with `-`, so options after the first term end up here
+        if let Some(option) = args.iter().find(|a| a.starts_with("--")) {
+            bail!("Put options before the query terms: {}", option);
+        }
+        let terms = args.iter()
+            .filter(|a| !a.trim().is_empty())
+            .map(|a| Term::parse(a.trim()))
+            .collect::<Result<_>>()?;
+        Ok(Self { terms })
+    }
+
+    pub fn matches(&self, p: &Purchase) -> bool {
+        self.terms.iter().all(|t| t.cond.matches(p) != t.negate)
+    }
+
+    /// Whether the query asks for `EXCLUDE`d purchases itself
+    pub fn mentions_excluded(&self) -> bool {
+        self.terms.iter().any(|t| t.cond == Condition::Tag("EXCLUDE".to_owned()))
+    }
+}
+
+impl Term {
+    fn parse(term: &str) -> Result<Self> {
+        let (negate, term) = match term.strip_prefix(['-', '!']) {
+            Some(rest) if !rest.is_empty() => (true, rest),
+            _ => (false, term),
+        };
+        let re = Regex::new(r"^([A-Za-z]+)(:|!=|>=|<=|=|>|<)(.+)$").unwrap();
+        let Some(caps) = re.captures(term) else {
+            return Ok(Self { negate, cond: Condition::Name(term.to_lowercase()) });
+        };
+        let (field, op, value) = (caps[1].to_lowercase(), &caps[2], caps[3].to_owned());
+        let (negate, cmp) = match op {
+            ":" | "=" => (negate, Cmp::Eq),
+            "!=" => (!negate, Cmp::Eq),
+            "<" => (negate, Cmp::Lt),
+            "<=" => (negate, Cmp::Le),
+            ">" => (negate, Cmp::Gt),
+            _ => (negate, Cmp::Ge),
+        };
+        if cmp != Cmp::Eq && !matches!(field.as_str(), "date" | "price") {
+            bail!("`{}` can only be compared with `:`, `=` or `!=`: {}", field, term);
+        }
+
+        let cond = match field.as_str() {
+            "tag" => Condition::Tag(value),
+            "store" => Condition::Store(value.to_lowercase()),
+            "name" => Condition::Name(value.to_lowercase()),
+            "receipt" => Condition::Receipt(value),
+            "date" => {
+                let (first, last) = date_range(&value).with_context(|| format!("Bad date in query term: {}", term))?;
+                Condition::Date(cmp, first, last)
+            }
+            "price" => {
+                let price = value.trim_start_matches('$').parse()
+                    .with_context(|| format!("Bad price in query term: {}", term))?;
+                Condition::Price(cmp, price)
+            }
+            "is" => match value.to_lowercase().as_str() {
+                "manual" => Condition::Manual,
+                "flagged" => Condition::LowConfidence,
+                _ => bail!("Unknown `is:` value, expected `manual` or `flagged`: {}", term),
+            },
+            _ => bail!("Unknown field `{}` in query term: {}; expected tag, store, name, receipt, date, price or is", field, term),
+        };
+        Ok(Self { negate, cond })
+    }
+}
+
+impl Condition {
+    fn matches(&self, p: &Purchase) -> bool {
+        match self {
+            Self::Tag(tag) => p.tags.contains(tag),
+            Self::Store(store) => p.store.as_ref().is_some_and(|s| s.to_lowercase() == *store),
+            Self::Name(name) => p.name.to_lowercase().contains(name),
+            Self::Receipt(receipt) => p.receipt.as_ref() == Some(receipt),
+            Self::Date(cmp, first, last) => match cmp {
+                Cmp::Eq => p.date >= *first && p.date <= *last,
+                Cmp::Lt => p.date < *first,
+                Cmp::Le => p.date <= *last,
+                Cmp::Gt => p.date > *last,
+                Cmp::Ge => p.date >= *first,
+            },
+            Self::Price(cmp, price) => {
+                let (a, b) = (round_cents(p.price), round_cents(*price));
+                match cmp {
+                    Cmp::Eq => a == b,
+                    Cmp::Lt => a < b,
+                    Cmp::Le => a <= b,
+                    Cmp::Gt => a > b,
+                    Cmp::Ge => a >= b,
+                }
+            }
+            Self::Manual => p.manual,
+            Self::LowConfidence => p.confidence.is_some(),
+        }
+    }
+}
+
+/// First and last day of `YYYY`, `YYYY-MM` or `YYYY-MM-DD`
+fn date_range(value: &str) -> Result<(NaiveDate, NaiveDate)> {
+    let parts: Vec<&str> = value.split('-').collect();
+    let num = |s: &str| s.parse::<u32>().with_context(|| format!("Not a number: {}", s));
+    let (first, last) = match parts.as_slice() {
+        [y] => {
+            let y = num(y)? as i32;
+            (NaiveDate::from_ymd_opt(y, 1, 1), NaiveDate::from_ymd_opt(y, 12, 31))
+        }
+        [y, m] => {
+            let first = NaiveDate::from_ymd_opt(num(y)? as i32, num(m)?, 1);
+            (first, first.and_then(|f| f.checked_add_months(chrono::Months::new(1))).and_then(|n| n.pred_opt()))
+        }
+        _ => {
+            let day = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok();
+            (day, day)
+        }
+    };
+    first.zip(last).with_context(|| format!("Expected YYYY, YYYY-MM or YYYY-MM-DD: {}", value))
+}
+
+impl GroupBy {
+    fn keys(&self, p: &Purchase) -> Vec<String> {
+        match self {
+            Self::Name => vec![p.name.clone()],
+            Self::Tag if p.tags.is_empty() => vec!["(untagged)".to_owned()],
+            Self::Tag => p.tags.clone(),
+            Self::Store => vec![p.store.clone().unwrap_or_else(|| "(no store)".to_owned())],
+            Self::Week => {
+                let week = p.date.iso_week();
+                vec![format!("{}-W{:02}", week.year(), week.week())]
+            }
+            Self::Month => vec![p.date.format("%Y-%m").to_string()],
+        }
+    }
+}
+
+impl Aggregate {
+    pub fn avg(&self) -> f64 {
+        if self.count == 0 { 0.0 } else { round_cents(self.sum / self.count as f64) }
+    }
+}
+
+/// Sum matching purchases per group. Weeks and months are in date order, the rest biggest first.
+pub fn group(matching: &[&Purchase], by: GroupBy) -> Vec<(String, Aggregate)> {
+    let mut groups: BTreeMap<String, Aggregate> = BTreeMap::new();
+    for p in matching {
+        for key in by.keys(p) {
+            let agg = groups.entry(key).or_default();
+            agg.count += 1;
+            agg.sum += p.price;
+        }
+    }
+    let mut groups: Vec<(String, Aggregate)> = groups.into_iter()
+        .map(|(k, a)| (k, Aggregate { sum: round_cents(a.sum), ..a }))
+        .collect();
+    if !matches!(by, GroupBy::Week | GroupBy::Month) {
+        groups.sort_by(|a, b| b.1.sum.total_cmp(&a.1.sum).then_with(|| a.0.cmp(&b.0)));
+    }
+    groups
+}
+
+/// Print the purchases matching `query`, or their totals per group. Purchases tagged `EXCLUDE`
+/// are left out, as in the monthly totals, unless `all` is set or the query asks for them.
+pub fn run(purchases: &Purchases, query: &Query, by: Option<GroupBy>, all: bool) {
+    let include_excluded = all || query.mentions_excluded();
+    let mut matching: Vec<&Purchase> = purchases.iter()
+        .filter(|p| include_excluded || !p.tags.iter().any(|t| t == "EXCLUDE"))
+        .filter(|p| query.matches(p))
+        .collect();
+    matching.sort_by_key(|p| (p.date, p.id));
+
+    match by {
+        Some(by) => {
+            let groups = group(&matching, by);
+            let width = groups.iter().map(|(k, _)| k.len()).max().unwrap_or(0).max(5);
+            println!("{:<width$}  {:>6}  {:>10}  {:>8}", "group", "count", "sum", "avg");
+            for (key, agg) in &groups {
+                println!("{:<width$}  {:>6}  {:>10.2}  {:>8.2}", key, agg.count, agg.sum, agg.avg());
+            }
+        }
+        None => {
+            for p in &matching {
+                println!("{}", purchases::line(p));
+            }
+        }
+    }
+    let total = Aggregate { count: matching.len(), sum: round_cents(matching.iter().fold(0.0, |sum, p| sum + p.price)) };
+    println!("{} purchases, sum {:.2}, avg {:.2}", total.count, total.sum, total.avg());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn purchase(name: &str, tags: &[&str], store: &str, price: f64, date: &str) -> Purchase {
+        Purchase {
+            name: name.to_owned(),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            price,
+            date: date.parse().unwrap(),
+            store: Some(store.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn query(q: &str) -> Query {
+        Query::parse(&q.split(' ').map(|s| s.to_owned()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn test_query_matches() {
+        let chips = purchase("Tortilla Chips", &["snacks"], "Costco", 12.99, "2024-02-10");
+        assert!(query("tag:snacks store:costco date>=2024-01 price>10").matches(&chips));
+        assert!(query("chips date:2024-02 price<=12.99").matches(&chips));
+        assert!(query("date<2024-03 date>2024-01 date=2024").matches(&chips));
+        assert!(!query("date<2024-02").matches(&chips));
+        assert!(!query("date>2024-02").matches(&chips));
+        assert!(query("date<=2024-02-10 -tag:produce store!=winco").matches(&chips));
+        assert!(!query("-chips").matches(&chips));
+        assert!(!query("!store:costco").matches(&chips));
+        assert!(!query("is:manual").matches(&chips));
+    }
+
+    #[test]
+    fn test_query_errors() {
+        for bad in ["--by", "colour:red", "price>ten", "date>=2024-13", "tag>snacks", "is:cheap"] {
+            assert!(Query::parse(&[bad.to_owned()]).is_err(), "{}", bad);
+        }
+        assert!(query("tag:EXCLUDE").mentions_excluded());
+    }
+
+    #[test]
+    fn test_group() {
+        let purchases = [
+            purchase("Chips", &["snacks"], "Costco", 10.0, "2024-01-01"),
+            purchase("Onions", &["veggies", "produce"], "WinCo", 1.5, "2024-01-08"),
+            purchase("Chips", &["snacks"], "WinCo", 5.0, "2024-02-01"),
+        ];
+        let matching: Vec<&Purchase> = purchases.iter().collect();
+
+        let by_name = group(&matching, GroupBy::Name);
+        assert_eq!(by_name[0], ("Chips".to_owned(), Aggregate { count: 2, sum: 15.0 }));
+        assert_eq!(by_name[0].1.avg(), 7.5);
+        assert_eq!(group(&matching, GroupBy::Tag).len(), 3);
+        let by_week: Vec<String> = group(&matching, GroupBy::Week).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(by_week, vec!["2024-W01", "2024-W02", "2024-W05"]);
+        let by_month = group(&matching, GroupBy::Month);
+        assert_eq!(by_month[0], ("2024-01".to_owned(), Aggregate { count: 2, sum: 11.5 }));
+    }
+}
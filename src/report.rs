@@ -0,0 +1,302 @@
+// © Zach Nielsen 2024
+
+use crate::data::{Purchase, Purchases};
+use crate::output::{Totals, round_cents};
+
+use anyhow::{Context, Result};
+use chrono::{Datelike, NaiveDate};
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+
+const PALETTE: [&str; 9] = [
+    "#4e79a7", "#f28e2b", "#e15759", "#76b7b2", "#59a14f",
+    "#edc948", "#b07aa1", "#ff9da7", "#9c755f",
+];
+const OTHER_COLOR: &str = "#bab0ac";
+const MAX_TAGS: usize = 8;
+const TOP_ITEMS: usize = 20;
+
+const CHART_W: f64 = 760.0;
+const CHART_H: f64 = 240.0;
+const PAD_L: f64 = 56.0;
+const PAD_B: f64 = 28.0;
+const PAD_T: f64 = 16.0;
+
+/// Totals for the purchases made in one calendar month
+pub struct Month {
+    pub year: i32,
+    pub month: u32,
+    pub totals: Totals,
+}
+
+struct ItemHistory<'a> {
+    name: &'a str,
+    total: f64,
+    prices: Vec<(NaiveDate, f64)>,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Totals for each of the `count` months ending with the month containing `end`, oldest first
+pub fn monthly_totals(purchases: &Purchases, end: NaiveDate, count: u32) -> Vec<Month> {
+    let mut months = Vec::new();
+    let (mut year, mut month) = (end.year(), end.month());
+    for _ in 0..count {
+        months.push((year, month));
+        if month == 1 {
+            year -= 1;
+            month = 12;
+        } else {
+            month -= 1;
+        }
+    }
+    months.reverse();
+
+    months.into_iter().map(|(year, month)| {
+        let keep: Vec<Purchase> = purchases.iter()
+            .filter(|p| p.date.year() == year && p.date.month() == month)
+            .cloned()
+            .collect();
+        let period = format!("{:04}-{:02}", year, month);
+        Month { year, month, totals: Totals::new(&period, &Purchases(keep)) }
+    }).collect()
+}
+
+pub fn write_html(purchases: &Purchases, months: u32, out: &Path) -> Result<()> {
+    let html = render_html(purchases, months);
+    std::fs::write(out, html)
+        .with_context(|| format!("Failed to write report: {}", out.display()))?;
+    println!("Wrote report to: {}", out.display());
+    Ok(())
+}
+
+fn render_html(purchases: &Purchases, months: u32) -> String {
+    let Some(end) = purchases.iter().map(|p| p.date).max() else {
+        return page("Itemizer Report", "<p>No purchases recorded yet.</p>");
+    };
+    let months = monthly_totals(purchases, end, months.max(1));
+    let first = &months[0];
+    let start = NaiveDate::from_ymd_opt(first.year, first.month, 1).unwrap();
+    let in_range = Purchases(purchases.iter().filter(|p| p.date >= start).cloned().collect());
+
+    let mut body = String::new();
+    let _ = writeln!(body, "<p class=\"sub\">{} to {}</p>", first.totals.period, months[months.len() - 1].totals.period);
+    body += "<h2>Monthly spend</h2>\n";
+    body += &monthly_chart(&months);
+    body += "<h2>Spend by tag</h2>\n";
+    body += &tag_chart(&months);
+    body += "<h2>Top items</h2>\n";
+    body += &top_items(&in_range);
+
+    page("Itemizer Report", &body)
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; max-width: 820px; margin: 2em auto; color: #222; }}
+h2 {{ margin-top: 2em; border-bottom: 1px solid #ddd; }}
+.sub {{ color: #666; }}
+table {{ border-collapse: collapse; width: 100%; }}
+td, th {{ padding: 4px 8px; border-bottom: 1px solid #eee; text-align: left; }}
+td.num {{ text-align: right; font-variant-numeric: tabular-nums; }}
+.legend span {{ display: inline-block; margin-right: 1em; }}
+.legend i {{ display: inline-block; width: 10px; height: 10px; margin-right: 4px; }}
+svg text {{ font-size: 11px; fill: #444; }}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+{body}
+</body>
+</html>
+"#, title = escape_html(title), body = body)
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Axis and month labels shared by both bar charts; returns the bar slot width
+fn chart_frame(svg: &mut String, months: &[Month], max_val: f64) -> f64 {
+    let plot_h = CHART_H - PAD_B - PAD_T;
+    let slot = (CHART_W - PAD_L) / months.len() as f64;
+    for i in 0..=4 {
+        let v = max_val * i as f64 / 4.0;
+        let y = PAD_T + plot_h - plot_h * i as f64 / 4.0;
+        let _ = writeln!(svg, "<line x1=\"{PAD_L}\" y1=\"{y:.1}\" x2=\"{CHART_W}\" y2=\"{y:.1}\" stroke=\"#eee\"/>");
+        let _ = writeln!(svg, "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\">{:.0}</text>", PAD_L - 6.0, y + 4.0, v);
+    }
+    for (i, m) in months.iter().enumerate() {
+        let x = PAD_L + slot * (i as f64 + 0.5);
+        let label = NaiveDate::from_ymd_opt(m.year, m.month, 1).unwrap().format("%b %y");
+        let _ = writeln!(svg, "<text x=\"{x:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{label}</text>", CHART_H - 8.0);
+    }
+    slot
+}
+
+fn monthly_chart(months: &[Month]) -> String {
+    let max_val = months.iter().map(|m| m.totals.total).fold(0.0, f64::max).max(1.0);
+    let plot_h = CHART_H - PAD_B - PAD_T;
+
+    let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{CHART_W}\" height=\"{CHART_H}\">\n");
+    let slot = chart_frame(&mut svg, months, max_val);
+    for (i, m) in months.iter().enumerate() {
+        let h = plot_h * m.totals.total / max_val;
+        let x = PAD_L + slot * i as f64 + slot * 0.15;
+        let y = PAD_T + plot_h - h;
+        let _ = writeln!(svg, "<rect x=\"{x:.1}\" y=\"{y:.1}\" width=\"{:.1}\" height=\"{h:.1}\" fill=\"{}\"><title>{}: {:.2}</title></rect>",
+            slot * 0.7, PALETTE[0], m.totals.period, m.totals.total);
+    }
+    svg += "</svg>\n";
+    svg
+}
+
+fn tag_chart(months: &[Month]) -> String {
+    // Rank tags over the whole range; everything past MAX_TAGS is lumped together
+    let mut ranked: HashMap<&str, f64> = HashMap::new();
+    for m in months {
+        for t in &m.totals.by_tag {
+            *ranked.entry(&t.tag).or_insert(0.0) += t.total;
+        }
+    }
+    let mut ranked: Vec<(&str, f64)> = ranked.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.0.cmp(b.0)));
+    let shown: Vec<&str> = ranked.iter().take(MAX_TAGS).map(|t| t.0).collect();
+    if shown.is_empty() {
+        return "<p>No tagged purchases in this period.</p>\n".to_owned();
+    }
+
+    let stacks: Vec<Vec<(&str, f64)>> = months.iter().map(|m| {
+        let mut stack: Vec<(&str, f64)> = shown.iter()
+            .map(|&tag| (tag, m.totals.by_tag.iter().find(|t| t.tag == tag).map(|t| t.total).unwrap_or(0.0)))
+            .collect();
+        let other: f64 = m.totals.by_tag.iter().filter(|t| !shown.contains(&t.tag.as_str())).map(|t| t.total).sum();
+        stack.push(("other", round_cents(other)));
+        stack
+    }).collect();
+    let max_val = stacks.iter()
+        .map(|s| s.iter().map(|t| t.1).sum::<f64>())
+        .fold(0.0, f64::max)
+        .max(1.0);
+    let plot_h = CHART_H - PAD_B - PAD_T;
+    let color = |i: usize| if i < shown.len() { PALETTE[i % PALETTE.len()] } else { OTHER_COLOR };
+
+    let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{CHART_W}\" height=\"{CHART_H}\">\n");
+    let slot = chart_frame(&mut svg, months, max_val);
+    for (i, (m, stack)) in months.iter().zip(&stacks).enumerate() {
+        let x = PAD_L + slot * i as f64 + slot * 0.15;
+        let mut y = PAD_T + plot_h;
+        for (j, (tag, val)) in stack.iter().enumerate() {
+            if *val <= 0.0 { continue; }
+            let h = plot_h * val / max_val;
+            y -= h;
+            let _ = writeln!(svg, "<rect x=\"{x:.1}\" y=\"{y:.1}\" width=\"{:.1}\" height=\"{h:.1}\" fill=\"{}\"><title>{} {}: {:.2}</title></rect>",
+                slot * 0.7, color(j), m.totals.period, escape_html(tag), val);
+        }
+    }
+    svg += "</svg>\n<p class=\"legend\">";
+    for (j, tag) in shown.iter().chain(std::iter::once(&"other")).enumerate() {
+        let _ = write!(svg, "<span><i style=\"background:{}\"></i>{}</span>", color(j), escape_html(tag));
+    }
+    svg += "</p>\n<p class=\"sub\">Purchases with several tags count towards each of them.</p>\n";
+    svg
+}
+
+fn top_items(purchases: &Purchases) -> String {
+    let mut items: HashMap<&str, ItemHistory> = HashMap::new();
+    for p in purchases.iter() {
+        if p.tags.contains(&"EXCLUDE".to_owned()) { continue; }
+        let item = items.entry(&p.name).or_insert(ItemHistory { name: &p.name, total: 0.0, prices: Vec::new() });
+        item.total += p.price;
+        item.prices.push((p.date, p.price));
+    }
+    let mut items: Vec<ItemHistory> = items.into_values().collect();
+    items.sort_by(|a, b| b.total.partial_cmp(&a.total).unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.name.cmp(b.name)));
+
+    let mut html = String::from("<table>\n<tr><th>#</th><th>Item</th><th>Total</th><th>Bought</th><th>Price history</th></tr>\n");
+    for (i, item) in items.iter_mut().take(TOP_ITEMS).enumerate() {
+        item.prices.sort_by_key(|p| p.0);
+        let prices: Vec<f64> = item.prices.iter().map(|p| p.1).collect();
+        let _ = writeln!(html, "<tr><td>{}</td><td>{}</td><td class=\"num\">{:.2}</td><td class=\"num\">{}</td><td>{}</td></tr>",
+            i + 1, escape_html(item.name), item.total, prices.len(), sparkline_svg(&prices));
+    }
+    html += "</table>\n";
+    html
+}
+
+fn sparkline_svg(values: &[f64]) -> String {
+    let (w, h) = (120.0, 24.0);
+    let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let span = if max > min { max - min } else { 1.0 };
+    let step = if values.len() > 1 { w / (values.len() - 1) as f64 } else { 0.0 };
+
+    let points: Vec<String> = values.iter().enumerate().map(|(i, v)| {
+        // Flat histories sit in the middle rather than on the floor
+        let y = if max > min { h - 2.0 - (v - min) / span * (h - 4.0) } else { h / 2.0 };
+        format!("{:.1},{:.1}", i as f64 * step, y)
+    }).collect();
+    let title = format!("{:.2} - {:.2}", min, max);
+    if values.len() == 1 {
+        return format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\"><title>{title}</title><circle cx=\"2\" cy=\"{:.1}\" r=\"2\" fill=\"{}\"/></svg>", h / 2.0, PALETTE[0]);
+    }
+    format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\"><title>{title}</title><polyline fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\" points=\"{}\"/></svg>",
+        PALETTE[0], points.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn purchase(name: &str, tags: &str, price: f64, date: &str) -> Purchase {
+        Purchase {
+            name: name.to_owned(),
+            tags: crate::data::split_tags(tags),
+            price,
+            date: date.parse().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_monthly_totals_spans_year_boundary() {
+        let purchases = Purchases(vec![
+            purchase("Onions", "produce", 1.29, "2023-12-05"),
+            purchase("Onions", "produce", 1.50, "2024-02-10"),
+        ]);
+        let months = monthly_totals(&purchases, "2024-02-10".parse().unwrap(), 3);
+        let periods: Vec<&str> = months.iter().map(|m| m.totals.period.as_str()).collect();
+        assert_eq!(periods, vec!["2023-12", "2024-01", "2024-02"]);
+        assert_eq!(months[0].totals.total, 1.29);
+        assert_eq!(months[1].totals.total, 0.0);
+    }
+
+    #[test]
+    fn test_html_is_self_contained_and_escaped() {
+        let purchases = Purchases(vec![
+            purchase("Mac & Cheese", "snacks", 2.50, "2024-07-01"),
+            purchase("Mac & Cheese", "snacks", 2.75, "2024-07-21"),
+        ]);
+        let html = render_html(&purchases, 12);
+        assert!(html.contains("Mac &amp; Cheese"));
+        assert!(html.contains("<polyline"));
+        assert!(!html.contains("src="));
+        assert!(!html.contains("<link"));
+    }
+
+    #[test]
+    fn test_html_no_purchases() {
+        let html = render_html(&Purchases(Vec::new()), 12);
+        assert!(html.contains("No purchases recorded yet."));
+    }
+}
@@ -0,0 +1,907 @@
+// © Zach Nielsen 2024
+
+use crate::archive::{self, ArchivedReceipt};
+use crate::cache::OcrCache;
+use crate::config::{Config, OcrConfig, PreprocessConfig};
+use crate::data::*;
+use crate::digital::{self, SourceKind};
+use crate::export::transactions;
+use crate::images;
+use crate::logging;
+use crate::ocr::{OcrEngine, OcrOutput};
+use crate::output::{OutputFormat, Totals};
+use crate::preprocess;
+use crate::sidecar::{LineReport, ReceiptReport, RuleReport};
+
+use anyhow::{Context, Result, bail};
+use chrono::NaiveDate;
+use regex::Regex;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ScanOptions {
+    /// Never run OCR; images without cached text are skipped
+    pub from_cache: bool,
+    /// Parse images that are already done too, replacing the purchases recorded from them
+    pub rescan: bool,
+    /// Images preprocessed and OCRed at once; 0 is treated as 1
+    pub jobs: usize,
+    /// Print what would be recorded without writing anything
+    pub dry_run: bool,
+}
+
+/// A receipt that has been OCRed, waiting to be merged into the purchases
+struct Scanned {
+    /// Paths of the receipt's photos, in order
+    parts: Vec<String>,
+    id: String,
+    date: NaiveDate,
+    /// Every part is already in the done file, so being rescanned
+    done: bool,
+    best: Attempt,
+    stats: Vec<(usize, String)>,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Preprocess and OCR a receipt's photos, using the store's settings when the file name says which
+/// store it is, then retry with other settings if the text doesn't parse well. Touches nothing but
+/// the upscaled images and the OCR cache, so it can run on any thread.
+fn ocr_image(parts: &[PathBuf], done: bool, config: &Config, ocr: &dyn OcrEngine, options: ScanOptions) -> Result<Scanned> {
+    let paths = parts.iter()
+        .map(|p| p.to_str().map(|s| s.to_owned()).context("Image path is not valid UTF-8"))
+        .collect::<Result<Vec<String>>>()?;
+    let entry_path = parts.first().context("Receipt has no images")?;
+    let entry_path_str = paths[0].as_str();
+    let mut names = parts.iter()
+        .map(|p| p.file_name().and_then(|n| n.to_str()).map(|n| n.to_owned()).context("Filename is not valid UTF-8"))
+        .collect::<Result<Vec<String>>>()?;
+
+    // Get the date from the file name
+    let date_re = Regex::new(r"\d{4}-\d{2}-\d{2}").unwrap();
+    let date_str = date_re.find(&names[0])
+        .with_context(|| format!("No date found in filename: {}", names[0]))?
+        .as_str();
+    let date = NaiveDate::parse_from_str(date_str, "%Y-%m-%d")
+        .with_context(|| format!("Invalid date in filename: {}", date_str))?;
+
+    let bytes = parts.iter()
+        .map(|p| std::fs::read(p).with_context(|| format!("Failed to read image: {}", p.display())))
+        .collect::<Result<Vec<_>>>()?;
+    let id = receipt_id(&bytes.concat());
+
+    let store_hint = ReceiptType::from_hint(&names[0]);
+    let store_key = store_hint.map(|s| s.key());
+    let _log = logging::receipt(entry_path_str, store_hint.map(|s| s.name()));
+    let cache = OcrCache::new(config.ocr_cache_dir());
+    let base = (config.preprocess_for(store_key), config.ocr_for(store_key));
+
+    // Digital receipts carry their text, so need no OCR
+    let kind = SourceKind::from_path(entry_path);
+    let text = match kind {
+        SourceKind::Html => Some(digital::html_text(&String::from_utf8_lossy(&bytes[0]))),
+        SourceKind::Pdf => digital::pdf_text(&bytes[0])?,
+        SourceKind::Image => None,
+    };
+    if let Some(text) = text {
+        log::debug!("Using embedded text from: {}", entry_path_str);
+        let best = Attempt::new(OcrOutput::from_text(text), base.1);
+        return Ok(Scanned { parts: paths, id, date, done, best, stats: Vec::new() });
+    }
+    // Upscaled images and rendered PDFs go in a directory of this receipt's own, kept until every
+    // attempt is done, so receipts with the same file name in other folders can't collide
+    let work = tempfile::Builder::new().prefix(".scan-").tempdir_in(&config.upscaled_image_dir)
+        .context("Failed to create a directory for upscaled images")?;
+    // PDFs of scanned paper are rendered to an image and OCRed like a photo
+    let raster = if kind == SourceKind::Pdf && !options.from_cache {
+        let raster = digital::rasterize_pdf(entry_path, work.path())?;
+        names[0] = format!("{}.png", names[0]);
+        Some(raster)
+    } else {
+        None
+    };
+    let images: Vec<&str> = match raster.as_ref().and_then(|r| r.to_str()) {
+        Some(raster) => vec![raster],
+        None => paths.iter().map(|p| p.as_str()).collect(),
+    };
+    let settings = std::iter::once(base.clone())
+        .chain(config.retry.attempts.iter().map(|a| a.apply(&base.0, &base.1)));
+
+    let mut best: Option<Attempt> = None;
+    let mut stats = Vec::new();
+    for (n, (pre, ocr_settings)) in settings.enumerate() {
+        if n > 0 {
+            log::info!("Retrying with other settings (attempt {}): {}", n, entry_path_str);
+        }
+        // Each attempt's upscaled image is removed before the next, so they can share a name
+        let outputs = (0..parts.len())
+            .map(|i| {
+                let source = Source { path: &paths[i], image: images[i], name: &names[i], bytes: &bytes[i] };
+                ocr_text(&source, work.path(), &pre, &ocr_settings, ocr, &cache, options)
+            })
+            .collect::<Result<Vec<_>>>();
+        let output = match outputs {
+            Ok(outputs) => stitch(outputs),
+            Err(e) if n > 0 => {
+                log::warn!("OCR attempt {} failed for {}: {:?}", n, entry_path_str, e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let attempt = Attempt { n, ..Attempt::new(output, ocr_settings) };
+        let steps: Vec<String> = pre.steps.iter().map(|s| format!("{:?}", s)).collect();
+        let psm = attempt.ocr.psm.map(|p| p.to_string()).unwrap_or_default();
+        stats.push((n, format!("{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            entry_path_str, attempt.store_name(), n, pre.scale, steps.join(","), psm, attempt.items)));
+
+        let good_enough = attempt.receipt.is_ok() && attempt.items >= config.retry.min_items;
+        if best.as_ref().is_none_or(|b| attempt.score() > b.score()) {
+            best = Some(attempt);
+        }
+        if good_enough {
+            break;
+        }
+    }
+    let best = best.context("No OCR attempts were made")?;
+
+    Ok(Scanned { parts: paths, id, date, done, best, stats })
+}
+
+/// Join the text of each photo of a receipt, dropping lines repeated where the photos overlap
+fn stitch(outputs: Vec<OcrOutput>) -> OcrOutput {
+    let mut outputs = outputs.into_iter();
+    let mut stitched = outputs.next().unwrap_or_else(|| OcrOutput::from_text(String::new()));
+    for next in outputs {
+        let dropped = stitched.append_overlapping(next);
+        if dropped > 0 {
+            log::debug!("Dropped {} lines repeated where photos overlap", dropped);
+        }
+    }
+    stitched
+}
+
+/// Record the purchases from an OCRed receipt and mark its photos done, returning its receipt id.
+/// `sole` says the receipt is the only one from its date, so every purchase from that date recorded
+/// before receipt ids existed is its.
+fn merge_scanned(scanned: Scanned, sole: bool, itemizer: &mut FileItemizer, options: ScanOptions) -> Result<String> {
+    let result = record_receipt(scanned, sole, itemizer, options);
+    // Ids of lines a rescan didn't read again mustn't go to the next receipt
+    itemizer.clear_replaced();
+    result
+}
+
+fn record_receipt(scanned: Scanned, sole: bool, itemizer: &mut FileItemizer, options: ScanOptions) -> Result<String> {
+    let Scanned { parts, id, date, done, best, stats } = scanned;
+    let path = parts.join(" + ");
+    let _log = logging::receipt(&parts[0], None);
+    // Digital receipts have no OCR attempts
+    if !stats.is_empty() && !options.dry_run {
+        if let Err(e) = record_attempts(&itemizer.config.retry_stats_file(), &stats, best.n) {
+            log::warn!("could not record OCR attempts: {:?}", e);
+        }
+    }
+    let Attempt { n, output, receipt, ocr: ocr_settings, .. } = best;
+    itemizer.set_date(date);
+    let mut report = ReceiptReport::new(&id, &parts, date, n, &output.text);
+
+    // Parse Receipt
+    let receipt = match receipt {
+        Ok(receipt) => receipt,
+        Err(e) => {
+            report.error = Some(format!("{:#}", e));
+            write_report(&report, itemizer, options);
+            return Err(e);
+        }
+    };
+    logging::set_store(receipt.store.name());
+    report.store = Some(receipt.store.name().to_owned());
+    if options.rescan {
+        // Reading the receipt again would undo corrections made by hand
+        let edited = itemizer.purchases.iter().filter(|p| p.edited && p.read_from(&id, date, receipt.store.name())).count();
+        if edited > 0 {
+            log::warn!("Keeping the purchases from {}: {} of them were changed with `purchases edit`", path, edited);
+            return Ok(id);
+        }
+        let replaced = itemizer.remove_receipt(&id, date, receipt.store.name(), done && sole);
+        log::info!("Replacing {} purchases from receipt: {}", replaced, path);
+    } else {
+        // Saved by a scan that stopped before the receipt was marked done
+        let before = itemizer.purchases.len();
+        itemizer.purchases.retain(|p| p.receipt.as_deref() != Some(id.as_str()));
+        if itemizer.purchases.len() < before {
+            log::info!("Replacing {} purchases saved by an interrupted scan: {}", before - itemizer.purchases.len(), path);
+        }
+    }
+    itemizer.set_receipt(receipt.store.name(), &id);
+    let mut matched = 0;
+    for (i, line) in receipt.text.lines().enumerate() {
+        let parsed = receipt.parse_line(line);
+        let mut entry = LineReport::new(i + 1, line, &parsed);
+        let LineParse::Item(code, desc, price) = parsed else {
+            if let Some(reason) = &entry.skipped {
+                log::debug!("Skipping line, {}: [{}]", reason, line);
+            }
+            report.lines.push(entry);
+            continue;
+        };
+        let rules_before = itemizer.maps.rules.len();
+        let purchase = itemizer.process_purchase(code, desc, price);
+        matched += 1;
+        let mut rule = RuleReport { name: purchase.name.clone(), tags: purchase.tags.clone(), new: false };
+
+        // Flag the purchase when the code or price was hard to read
+        let conf = receipt.field_text(line)
+            .and_then(|(code_text, price_text)| output.field_confidence(i, &[code_text, price_text]));
+        if let Some(conf) = conf.filter(|c| *c < ocr_settings.min_confidence) {
+            purchase.confidence = Some(conf.round() as u8);
+            entry.confidence = Some(conf);
+            log::debug!("Low confidence ({:.0}): [{}]", conf, line);
+        }
+        rule.new = itemizer.maps.rules.len() > rules_before;
+        entry.rule = Some(rule);
+        report.lines.push(entry);
+    }
+    log::info!("Matched {} item lines from {} receipt: {}", matched, receipt.store.name(), path);
+    // Other receipts from the same day may hold purchases from before receipt ids, so only the ones
+    // read again here are replaced
+    let legacy = itemizer.legacy_count(date, receipt.store.name());
+    if options.rescan && legacy > 0 {
+        if itemizer.replace_legacy(&id, date, receipt.store.name()) {
+            log::info!("Replaced {} purchases recorded before receipt ids", legacy - itemizer.legacy_count(date, receipt.store.name()));
+        } else {
+            log::warn!("Keeping {} purchases from {} recorded before receipt ids, as they may be from another \
+                receipt and don't match this one: {}", legacy, date, path);
+        }
+    }
+    report.reconcile(receipt.printed_total());
+    write_report(&report, itemizer, options);
+
+    // Commit the receipt. Purchases and rules are saved before the done marker, so a receipt is
+    // never done without its purchases; one saved but not marked done is replaced when scanned again.
+    if options.dry_run {
+        return Ok(id);
+    }
+    itemizer.save_to_disk()?;
+    if done {
+        return Ok(id);
+    }
+    let mut done_fp = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&itemizer.config.done_file)
+        .context("Failed to open done file for writing")?;
+    for part in &parts {
+        if !image_done(part, &itemizer.config.done_file)? {
+            writeln!(done_fp, "{}", part)?;
+        }
+    }
+    drop(done_fp);
+
+    let archived = ArchivedReceipt { date, store: receipt.store.key(), id: &id };
+    if let Err(e) = archive::archive(&parts, &archived, &itemizer.config.archive, &itemizer.config.done_file) {
+        log::warn!("could not archive receipt {}: {:?}", path, e);
+    }
+
+    Ok(id)
+}
+
+/// The image being scanned
+struct Source<'a> {
+    path: &'a str,
+    /// Image to preprocess; differs from `path` for rendered PDFs
+    image: &'a str,
+    /// File name for the preprocessed image
+    name: &'a str,
+    bytes: &'a [u8],
+}
+
+/// One pass of preprocessing and OCR over a receipt
+struct Attempt {
+    n: usize,
+    ocr: OcrConfig,
+    output: OcrOutput,
+    receipt: Result<Receipt>,
+    items: usize,
+}
+
+impl Attempt {
+    fn new(output: OcrOutput, settings: OcrConfig) -> Self {
+        let receipt = Receipt::new(output.text.clone());
+        let items = match &receipt {
+            Ok(r) => r.text.lines().filter(|l| r.get_fields(l).is_some()).count(),
+            Err(_) => 0,
+        };
+        Self { n: 0, ocr: settings, output, receipt, items }
+    }
+
+    /// Reading the store matters most, then the number of item lines
+    fn score(&self) -> (bool, usize) {
+        (self.receipt.is_ok(), self.items)
+    }
+
+    fn store_name(&self) -> &str {
+        self.receipt.as_ref().map(|r| r.store.name()).unwrap_or("unknown")
+    }
+}
+
+fn write_report(report: &ReceiptReport, itemizer: &FileItemizer, options: ScanOptions) {
+    if options.dry_run {
+        return;
+    }
+    if let Err(e) = report.write(&itemizer.config.receipt_report_dir()) {
+        log::warn!("could not write receipt report: {:?}", e);
+    }
+}
+
+/// Preprocess and OCR an image, or reuse its cached text from an earlier scan
+fn ocr_text(source: &Source, work_dir: &Path, pre: &PreprocessConfig, settings: &OcrConfig,
+            ocr: &dyn OcrEngine, cache: &OcrCache, options: ScanOptions) -> Result<OcrOutput> {
+    let key = OcrCache::key(source.bytes, pre, settings)?;
+    let output = match cache.get(&key) {
+        Some(output) => {
+            log::debug!("Using cached OCR text for: {}", source.path);
+            output
+        }
+        None if options.from_cache => bail!("No cached OCR text for: {}", source.path),
+        None => {
+            let resized_path = preprocess::preprocess_image(source.image, work_dir, source.name, pre)?;
+            let output = ocr.recognize(Path::new(&resized_path), settings);
+
+            // Clean up upscaled image
+            if let Err(e) = std::fs::remove_file(&resized_path) {
+                log::warn!("could not clean up upscaled image {}: {}", resized_path, e);
+            }
+            let output = output?;
+            if options.dry_run {
+                return Ok(output);
+            }
+            if let Err(e) = cache.put(&key, &output) {
+                log::warn!("could not cache OCR text: {:?}", e);
+            }
+            output
+        }
+    };
+
+    Ok(output)
+}
+
+/// Append one row per attempt, marking the one that was kept
+fn record_attempts(stats_file: &Path, rows: &[(usize, String)], kept: usize) -> Result<()> {
+    let new = !stats_file.exists();
+    let mut fp = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(stats_file)
+        .with_context(|| format!("Failed to open OCR attempts file: {}", stats_file.display()))?;
+    if new {
+        writeln!(fp, "image\tstore\tattempt\tscale\tsteps\tpsm\titems\tkept")?;
+    }
+    for (n, row) in rows {
+        writeln!(fp, "{}\t{}", row, *n == kept)?;
+    }
+    Ok(())
+}
+
+/// Photos of one long receipt are numbered after the date and store, e.g. `2024-07-21-costco-1.jpg`
+/// and `2024-07-21-costco-2.jpg`. Group them in part order; every other file is a receipt of its
+/// own. Names with only a date and number, like `2024-07-21-1.jpg`, are separate receipts from one
+/// day.
+pub fn group_parts(paths: Vec<PathBuf>) -> Vec<Vec<PathBuf>> {
+    let part_re = Regex::new(r"^(.*\d{4}-\d{2}-\d{2}.*)-(\d{1,2})$").unwrap();
+    let date_re = Regex::new(r"\d{4}-\d{2}-\d{2}").unwrap();
+    let mut groups: Vec<Vec<(u32, PathBuf)>> = Vec::new();
+    let mut group_of: HashMap<String, usize> = HashMap::new();
+    for path in paths {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        let part = part_re.captures(&stem)
+            .filter(|_| SourceKind::from_path(&path) == SourceKind::Image)
+            .filter(|c| date_re.replace(&c[1], "").chars().any(|ch| ch.is_alphabetic()))
+            .map(|c| (c[1].to_owned(), c[2].parse().unwrap_or(0)));
+        let Some((base, n)) = part else {
+            groups.push(vec![(0, path)]);
+            continue;
+        };
+        match group_of.get(&base) {
+            Some(&g) => groups[g].push((n, path)),
+            None => {
+                group_of.insert(base, groups.len());
+                groups.push(vec![(n, path)]);
+            }
+        }
+    }
+    groups.into_iter()
+        .map(|mut parts| {
+            parts.sort_by_key(|p| p.0);
+            parts.into_iter().map(|p| p.1).collect()
+        })
+        .collect()
+}
+
+pub fn parse_files(mut itemizer: FileItemizer, ocr: &dyn OcrEngine, format: OutputFormat, options: ScanOptions) -> Result<()> {
+    let mut files = images::image_files(&itemizer.config)?;
+    if options.rescan {
+        files.paths.extend(images::archived_files(&itemizer.config)?);
+    }
+    // Upscaled images and rendered PDFs go somewhere that is cleaned up afterwards
+    let _scratch = if options.dry_run {
+        let dir = tempfile::tempdir().context("Failed to create a temporary directory for the dry run")?;
+        itemizer.config.upscaled_image_dir = dir.path().to_owned();
+        Some(dir)
+    } else {
+        None
+    };
+    let rules_before = itemizer.maps.rules.len();
+
+    let scanned = scan_paths(&mut itemizer, files.paths, ocr, options)?;
+    print_flagged(&itemizer, &scanned);
+    if files.skipped > 0 {
+        log::warn!("Skipped {} files that aren't receipts or are filtered out by [images]", files.skipped);
+    }
+    if options.dry_run {
+        print_dry_run(&itemizer, &scanned, rules_before);
+    }
+
+    Totals::new("all", itemizer.purchases()).print(format)?;
+    Ok(())
+}
+
+/// List the purchases and rules a scan would have added
+fn print_dry_run(itemizer: &FileItemizer, scanned: &HashSet<String>, rules_before: usize) {
+    for tx in transactions(itemizer.purchases()).iter().filter(|tx| scanned.contains(&tx.id)) {
+        eprintln!("\nWould add receipt {} from {} on {}:", tx.id, tx.payee, tx.date);
+        for p in &tx.purchases {
+            eprintln!("  {:>8.2}  {}  [{}]", p.price, p.name, p.tags.join(", "));
+        }
+    }
+    let new_rules = &itemizer.maps.rules[rules_before..];
+    if !new_rules.is_empty() {
+        eprintln!("\nWould add {} rules for unknown items:", new_rules.len());
+        for rule in new_rules {
+            eprintln!("  {}  {}", rule.code, rule.desc);
+        }
+    }
+    eprintln!("\nDry run: nothing was written");
+}
+
+/// Scan receipts that aren't done yet (or all of them, when rescanning), returning the ids of the
+/// receipts parsed
+pub fn scan_paths(itemizer: &mut FileItemizer, mut paths: Vec<PathBuf>, ocr: &dyn OcrEngine, options: ScanOptions) -> Result<HashSet<String>> {
+    // Sort by filename for deterministic date-ordered processing
+    paths.sort_by_key(|p| p.file_name().map(|n| n.to_owned()));
+
+    // Receipts per date, to tell which purchases from before receipt ids a rescanned one can claim
+    let groups = group_parts(paths);
+    let date_re = Regex::new(r"\d{4}-\d{2}-\d{2}").unwrap();
+    let date_of = |parts: &[PathBuf]| date_re.find(&parts[0].file_name().unwrap_or_default().to_string_lossy())
+        .map(|d| d.as_str().to_owned());
+    let mut per_date: HashMap<String, usize> = HashMap::new();
+    for date in groups.iter().filter_map(|parts| date_of(parts)) {
+        *per_date.entry(date).or_default() += 1;
+    }
+
+    let mut work = Vec::new();
+    for parts in groups {
+        let mut done = true;
+        for part in &parts {
+            done &= image_done(&part.to_string_lossy(), &itemizer.config.done_file)?;
+        }
+        if done && !options.rescan {
+            log::debug!("Receipt already done, skipping: {}", parts[0].display());
+            continue;
+        }
+        let sole = date_of(&parts).is_some_and(|d| per_date[&d] == 1);
+        work.push((parts, done, sole));
+    }
+
+    // OCR on a pool of workers, merging results in filename order as they become available
+    let config = itemizer.config.clone();
+    let next = AtomicUsize::new(0);
+    let mut scanned = HashSet::new();
+    std::thread::scope(|s| {
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..options.jobs.clamp(1, work.len().max(1)) {
+            let (tx, next, work, config) = (tx.clone(), &next, &work, &config);
+            s.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some((parts, done, _)) = work.get(i) else { break };
+                let result = ocr_image(parts, *done, config, ocr, options);
+                if tx.send((i, result)).is_err() { break; }
+            });
+        }
+        drop(tx);
+
+        let mut pending = BTreeMap::new();
+        let mut next_merge = 0;
+        for (i, result) in rx {
+            pending.insert(i, result);
+            while let Some(result) = pending.remove(&next_merge) {
+                match result.and_then(|r| merge_scanned(r, work[next_merge].2, itemizer, options)) {
+                    Ok(id) => { scanned.insert(id); }
+                    Err(e) => log::error!("could not process {:?}: {:?}", work[next_merge].0[0], e),
+                }
+                next_merge += 1;
+            }
+        }
+    });
+    Ok(scanned)
+}
+
+/// List purchases from the given receipts that were read with low confidence
+pub fn print_flagged(itemizer: &FileItemizer, receipts: &HashSet<String>) {
+    let flagged: Vec<_> = itemizer.purchases().iter()
+        .filter(|p| p.confidence.is_some() && p.receipt.as_ref().is_some_and(|r| receipts.contains(r)))
+        .collect();
+    if !flagged.is_empty() {
+        eprintln!("\n{} purchases read with low confidence, check them against the receipt:", flagged.len());
+        for p in flagged {
+            eprintln!("  {} {:<12} {:>8.2}  {}  (conf {})", p.date, p.store.as_deref().unwrap_or(""),
+                p.price, p.name, p.confidence.unwrap_or(0));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::ocr::FixtureOcr;
+    use crate::purchases::PurchaseEdit;
+
+    use std::path::PathBuf;
+
+    /// Data directory with a blank image per fixture text, and rules for one item
+    fn setup(receipts: &[(&str, &str)]) -> (tempfile::TempDir, Config, FixtureOcr) {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::in_data_dir(dir.path());
+        let fixtures: PathBuf = dir.path().join("fixtures");
+        for d in [&config.image_dir, &config.upscaled_image_dir, &fixtures] {
+            std::fs::create_dir_all(d).unwrap();
+        }
+        std::fs::write(&config.rules_file, "4093\nONION YLW CO\nOnions\nveggies, produce\n").unwrap();
+
+        for (i, (name, text)) in receipts.iter().enumerate() {
+            // Vary the pixels so each image gets its own receipt id
+            let img = image::GrayImage::from_pixel(8, 8, image::Luma([200 + i as u8]));
+            img.save(config.image_dir.join(name)).unwrap();
+            std::fs::write(fixtures.join(format!("{}.txt", name)), text).unwrap();
+        }
+        (dir, config, FixtureOcr { dir: fixtures })
+    }
+
+    #[test]
+    fn test_scan_pipeline_with_fixture_ocr() {
+        let (_dir, config, ocr) = setup(&[
+            ("2024-07-21-winco.png", "WinCo Foods\nONION YLW CO 4093 1.29\nFRT BAR APL/FI 85313200796 3.50\nTOTAL 4.79\n"),
+            ("2024-07-22-costco.png", "COSTCO WHOLESALE\n1234567 ORGANIC MILK 5.99\n"),
+        ]);
+        let done_file = config.done_file.clone();
+        let purchases_file = config.purchases_file.clone();
+        let rules_file = config.rules_file.clone();
+        let upscaled_dir = config.upscaled_image_dir.clone();
+
+        parse_files(FileItemizer::new(config).unwrap(), &ocr, OutputFormat::Json, ScanOptions::default()).unwrap();
+
+        let purchases = Purchases::init(&purchases_file).unwrap();
+        assert_eq!(purchases.len(), 3);
+        assert_eq!(purchases[0].name, "Onions");
+        assert_eq!(purchases[0].date, NaiveDate::from_ymd_opt(2024, 7, 21).unwrap());
+        assert_eq!(purchases[0].store.as_deref(), Some("WinCo"));
+        assert_eq!(purchases[2].store.as_deref(), Some("Costco"));
+        assert_ne!(purchases[0].receipt, purchases[2].receipt);
+
+        // Unrecognised items are queued in the rules file for the user
+        let maps = ItemMaps::init(&rules_file).unwrap();
+        assert_eq!(maps.rules.len(), 3);
+        assert_eq!(maps.rules[maps.codes[&85313200796]].name, "UNKNOWN");
+
+        assert_eq!(std::fs::read_to_string(&done_file).unwrap().lines().count(), 2);
+        assert_eq!(std::fs::read_dir(&upscaled_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_scan_flags_low_confidence() {
+        let (_dir, config, ocr) = setup(&[]);
+        image::GrayImage::from_pixel(8, 8, image::Luma([200])).save(config.image_dir.join("2024-07-21-winco.png")).unwrap();
+        std::fs::write(ocr.dir.join("2024-07-21-winco.png.tsv"), crate::ocr::fixture_tsv(&[
+            &[("WinCo", 95.0), ("Foods", 95.0)],
+            &[("ONION", 90.0), ("YLW", 90.0), ("CO", 90.0), ("4093", 92.0), ("1.29", 31.0)],
+            &[("MILK", 12.0), ("1234", 90.0), ("5.99", 88.0)],
+        ])).unwrap();
+        let purchases_file = config.purchases_file.clone();
+
+        parse_files(FileItemizer::new(config).unwrap(), &ocr, OutputFormat::Json, ScanOptions::default()).unwrap();
+
+        let purchases = Purchases::init(&purchases_file).unwrap();
+        assert_eq!(purchases.len(), 2);
+        assert_eq!(purchases[0].confidence, Some(31));
+        // A blurry description alone isn't worth flagging
+        assert_eq!(purchases[1].confidence, None);
+    }
+
+    /// Only reads the receipt properly with page segmentation mode 4
+    struct Psm4Ocr;
+
+    impl OcrEngine for Psm4Ocr {
+        fn recognize(&self, _image: &Path, settings: &OcrConfig) -> Result<OcrOutput> {
+            let text = match settings.psm {
+                Some(4) => "WinCo Foods\nONION YLW CO 4093 1.29\nMILK 1234 3.49\n",
+                _ => "W1nC0 F0ods\nONI0N YLW C0 4O93 l.29\n",
+            };
+            Ok(OcrOutput::from_text(text.to_owned()))
+        }
+    }
+
+    #[test]
+    fn test_scan_retries_until_receipt_parses() {
+        let (dir, config, _) = setup(&[("2024-07-21-winco.png", "")]);
+        let purchases_file = config.purchases_file.clone();
+        let stats_file = config.retry_stats_file();
+
+        parse_files(FileItemizer::new(config).unwrap(), &Psm4Ocr, OutputFormat::Json, ScanOptions::default()).unwrap();
+
+        assert_eq!(Purchases::init(&purchases_file).unwrap().len(), 2);
+        // The default second retry is the first to use psm 4
+        let stats = std::fs::read_to_string(&stats_file).unwrap();
+        let kept: Vec<&str> = stats.lines().skip(1).map(|l| l.rsplit('\t').next().unwrap()).collect();
+        assert_eq!(kept, vec!["false", "false", "true"]);
+        assert!(stats.lines().nth(3).unwrap().contains("\tWinCo\t2\t2\t"));
+        assert!(stats_file.starts_with(dir.path()));
+    }
+
+    #[test]
+    fn test_rescan_from_cache_replaces_purchases() {
+        let (dir, config, ocr) = setup(&[("2024-07-21-winco.png", "WinCo Foods\nONION YLW CO 4093 1.29\nONION YLW CO 4093 1.30\n")]);
+        let purchases_file = config.purchases_file.clone();
+        let rules_file = config.rules_file.clone();
+        parse_files(FileItemizer::new(config).unwrap(), &ocr, OutputFormat::Json, ScanOptions::default()).unwrap();
+
+        // Rename the item and make OCR unavailable; rescanning must work from the cache alone
+        std::fs::write(&rules_file, "4093\nONION YLW CO\nYellow Onions\nveggies, produce\n").unwrap();
+        std::fs::remove_dir_all(&ocr.dir).unwrap();
+        let options = ScanOptions { from_cache: true, rescan: true, ..ScanOptions::default() };
+        parse_files(FileItemizer::new(Config::in_data_dir(dir.path())).unwrap(), &ocr, OutputFormat::Json, options).unwrap();
+
+        let purchases = Purchases::init(&purchases_file).unwrap();
+        assert_eq!(purchases.len(), 2);
+        assert!(purchases.iter().all(|p| p.name == "Yellow Onions"));
+        let done = std::fs::read_to_string(dir.path().join("done")).unwrap();
+        assert_eq!(done.lines().count(), 1);
+    }
+
+    #[test]
+    fn test_rescan_keeps_ids_and_edits() {
+        let (dir, config, ocr) = setup(&[
+            ("2024-07-21-winco.png", "WinCo Foods\nONION YLW CO 4093 1.29\nONION YLW CO 4093 1.30\n"),
+            ("2024-07-22-winco.png", "WinCo Foods\nONION YLW CO 4093 2.00\n"),
+        ]);
+        let purchases_file = config.purchases_file.clone();
+        let cache_dir = config.ocr_cache_dir();
+        parse_files(FileItemizer::new(config).unwrap(), &ocr, OutputFormat::Json, ScanOptions::default()).unwrap();
+
+        // Fix the name of the second receipt's only item by hand
+        let mut itemizer = FileItemizer::new(Config::in_data_dir(dir.path())).unwrap();
+        PurchaseEdit { name: Some("Sweet Onions".into()), ..PurchaseEdit::default() }.apply(itemizer.purchases.get_mut(3).unwrap()).unwrap();
+        itemizer.save_to_disk().unwrap();
+
+        // The second line is gone from the first receipt now
+        std::fs::write(ocr.dir.join("2024-07-21-winco.png.txt"), "WinCo Foods\nONION YLW CO 4093 1.30\n").unwrap();
+        std::fs::remove_dir_all(cache_dir).unwrap();
+        let options = ScanOptions { rescan: true, ..ScanOptions::default() };
+        parse_files(FileItemizer::new(Config::in_data_dir(dir.path())).unwrap(), &ocr, OutputFormat::Json, options).unwrap();
+
+        let purchases = Purchases::init(&purchases_file).unwrap();
+        let rows: Vec<(u64, f64, &str)> = purchases.iter().map(|p| (p.id, p.price, p.name.as_str())).collect();
+        assert_eq!(rows, vec![(3, 2.00, "Sweet Onions"), (2, 1.30, "Onions")]);
+    }
+
+    #[test]
+    fn test_rescan_keeps_legacy_purchases_of_other_receipts() {
+        let (_dir, config, ocr) = setup(&[
+            ("2024-07-21-winco.png", "WinCo Foods\nONION YLW CO 4093 1.29\n"),
+            ("2024-07-21-costco.png", "COSTCO WHOLESALE\n1234567 ORGANIC MILK 5.99\n"),
+        ]);
+        // Both scanned before receipt ids and stores were saved; the Costco photo can't be read now
+        std::fs::write(&config.purchases_file, "2024-07-21 | 1.29 | Onions | veggies, produce\n\
+            2024-07-21 | 5.99 | Milk | dairy\n").unwrap();
+        let done: Vec<String> = ["2024-07-21-winco.png", "2024-07-21-costco.png"].iter()
+            .map(|n| config.image_dir.join(n).to_string_lossy().into_owned()).collect();
+        std::fs::write(&config.done_file, done.join("\n") + "\n").unwrap();
+        std::fs::remove_file(ocr.dir.join("2024-07-21-costco.png.txt")).unwrap();
+        let purchases_file = config.purchases_file.clone();
+
+        let options = ScanOptions { rescan: true, ..ScanOptions::default() };
+        parse_files(FileItemizer::new(config).unwrap(), &ocr, OutputFormat::Json, options).unwrap();
+
+        let purchases = Purchases::init(&purchases_file).unwrap();
+        let rows: Vec<(u64, &str, bool)> = purchases.iter().map(|p| (p.id, p.name.as_str(), p.receipt.is_some())).collect();
+        assert_eq!(rows, vec![(2, "Milk", false), (1, "Onions", true)]);
+    }
+
+    #[test]
+    fn test_archived_receipt_can_be_rescanned() {
+        let (dir, mut config, ocr) = setup(&[("2024-07-21-winco.png", "WinCo Foods\nONION YLW CO 4093 1.29\n")]);
+        config.archive.dir = Some(dir.path().join("archive"));
+        let image = config.image_dir.join("2024-07-21-winco.png");
+        let purchases_file = config.purchases_file.clone();
+        parse_files(FileItemizer::new(config.clone()).unwrap(), &ocr, OutputFormat::Json, ScanOptions::default()).unwrap();
+
+        let id = Purchases::init(&purchases_file).unwrap()[0].receipt.clone().unwrap();
+        let archived = dir.path().join(format!("archive/2024/07/winco/2024-07-21-winco-{}.png", id));
+        assert!(!image.exists());
+        assert!(archived.exists());
+        let done = std::fs::read_to_string(dir.path().join("done")).unwrap();
+        assert_eq!(done.trim(), archived.to_string_lossy());
+
+        let options = ScanOptions { from_cache: true, rescan: true, ..ScanOptions::default() };
+        parse_files(FileItemizer::new(config).unwrap(), &ocr, OutputFormat::Json, options).unwrap();
+        assert_eq!(Purchases::init(&purchases_file).unwrap().len(), 1);
+        assert!(archived.exists());
+    }
+
+    #[test]
+    fn test_dry_run_writes_nothing() {
+        let (dir, config, ocr) = setup(&[("2024-07-21-winco.png", "WinCo Foods\nONION YLW CO 4093 1.29\nMYSTERY ITEM 555 2.00\n")]);
+        let rules = std::fs::read_to_string(&config.rules_file).unwrap();
+        let upscaled_dir = config.upscaled_image_dir.clone();
+        let options = ScanOptions { dry_run: true, ..ScanOptions::default() };
+
+        parse_files(FileItemizer::new(config).unwrap(), &ocr, OutputFormat::Json, options).unwrap();
+
+        assert!(!dir.path().join("done").exists());
+        assert!(!dir.path().join("purchases").exists());
+        assert!(!dir.path().join("ocr_cache").exists());
+        assert!(!dir.path().join("ocr_attempts.tsv").exists());
+        assert_eq!(std::fs::read_to_string(dir.path().join("rules")).unwrap(), rules);
+        assert_eq!(std::fs::read_dir(upscaled_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_interrupted_scan_is_not_duplicated() {
+        let (dir, config, ocr) = setup(&[("2024-07-21-winco.png", "WinCo Foods\nONION YLW CO 4093 1.29\n")]);
+        let purchases_file = config.purchases_file.clone();
+        parse_files(FileItemizer::new(config.clone()).unwrap(), &ocr, OutputFormat::Json, ScanOptions::default()).unwrap();
+
+        // Purchases were saved, but the process died before the done marker was written
+        std::fs::remove_file(dir.path().join("done")).unwrap();
+        parse_files(FileItemizer::new(config).unwrap(), &ocr, OutputFormat::Json, ScanOptions::default()).unwrap();
+
+        assert_eq!(Purchases::init(&purchases_file).unwrap().len(), 1);
+        assert!(dir.path().join("done").exists());
+        assert!(!dir.path().join(".purchases.tmp").exists());
+    }
+
+    #[test]
+    fn test_receipt_report() {
+        let (dir, config, ocr) = setup(&[("2024-07-21-winco.png", "WinCo Foods\nONION YLW CO 4093 1.29\nMYSTERY ITEM 555 2.00\nSUBTOTAL 3.79\n")]);
+        let purchases_file = config.purchases_file.clone();
+        parse_files(FileItemizer::new(config).unwrap(), &ocr, OutputFormat::Json, ScanOptions::default()).unwrap();
+
+        let id = Purchases::init(&purchases_file).unwrap()[0].receipt.clone().unwrap();
+        let report = std::fs::read_to_string(dir.path().join(format!("receipt_reports/{}.json", id))).unwrap();
+        let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(report["store"], "WinCo");
+        assert_eq!(report["lines"][0]["matched"], false);
+        assert_eq!(report["lines"][1]["fields"]["code"], 4093);
+        assert_eq!(report["lines"][1]["rule"]["name"], "Onions");
+        assert_eq!(report["lines"][2]["rule"]["new"], true);
+        assert_eq!(report["reconciliation"]["items_total"], 3.29);
+        assert_eq!(report["reconciliation"]["difference"], 0.5);
+    }
+
+    #[test]
+    fn test_scan_from_cache_skips_uncached() {
+        let (_dir, config, ocr) = setup(&[("2024-07-21-winco.png", "WinCo Foods\nONION YLW CO 4093 1.29\n")]);
+        let done_file = config.done_file.clone();
+        let options = ScanOptions { from_cache: true, ..ScanOptions::default() };
+
+        parse_files(FileItemizer::new(config).unwrap(), &ocr, OutputFormat::Json, options).unwrap();
+
+        assert!(!done_file.exists());
+    }
+
+    #[test]
+    fn test_parallel_scan_merges_in_filename_order() {
+        let receipts: Vec<(String, String)> = (1..=6)
+            .map(|d| (format!("2024-07-0{}-winco.png", d), format!("WinCo Foods\nONION YLW CO 4093 {}.29\n", d)))
+            .collect();
+        let receipts: Vec<(&str, &str)> = receipts.iter().map(|(n, t)| (n.as_str(), t.as_str())).collect();
+        let (_dir, config, ocr) = setup(&receipts);
+        let done_file = config.done_file.clone();
+        let purchases_file = config.purchases_file.clone();
+        let options = ScanOptions { jobs: 4, ..ScanOptions::default() };
+
+        parse_files(FileItemizer::new(config).unwrap(), &ocr, OutputFormat::Json, options).unwrap();
+
+        let prices: Vec<f64> = Purchases::init(&purchases_file).unwrap().iter().map(|p| p.price).collect();
+        assert_eq!(prices, vec![1.29, 2.29, 3.29, 4.29, 5.29, 6.29]);
+        let done = std::fs::read_to_string(&done_file).unwrap();
+        let done: Vec<&str> = done.lines().collect();
+        let mut sorted = done.clone();
+        sorted.sort();
+        assert_eq!(done.len(), 6);
+        assert_eq!(done, sorted);
+    }
+
+    #[test]
+    fn test_scan_html_receipt_without_ocr() {
+        let (_dir, config, ocr) = setup(&[]);
+        std::fs::write(config.image_dir.join("2024-07-21-fredmeyer.html"),
+            "<html><body><h2>Fred Meyer</h2><table>\
+             <tr><td>4093</td><td>ONION YLW CO</td><td>$1.29</td><td>F</td></tr>\
+             </table></body></html>").unwrap();
+        let purchases_file = config.purchases_file.clone();
+        let stats_file = config.retry_stats_file();
+
+        parse_files(FileItemizer::new(config).unwrap(), &ocr, OutputFormat::Json, ScanOptions::default()).unwrap();
+
+        let purchases = Purchases::init(&purchases_file).unwrap();
+        assert_eq!(purchases.len(), 1);
+        assert_eq!(purchases[0].name, "Onions");
+        assert_eq!(purchases[0].store.as_deref(), Some("Fred Meyer"));
+        assert!(!stats_file.exists());
+    }
+
+    #[test]
+    fn test_group_parts() {
+        let paths = ["2024-07-21-1.jpg", "2024-07-21-2.jpg", "2024-07-21-costco-1.jpg", "2024-07-21-costco-10.jpg",
+                     "2024-07-21-costco-2.jpg", "2024-07-21-winco.jpg", "2024-07-22-costco-1.pdf"];
+        let groups = group_parts(paths.iter().map(PathBuf::from).collect());
+        let names: Vec<Vec<&str>> = groups.iter()
+            .map(|g| g.iter().map(|p| p.to_str().unwrap()).collect())
+            .collect();
+        assert_eq!(names, vec![
+            vec!["2024-07-21-1.jpg"],
+            vec!["2024-07-21-2.jpg"],
+            vec!["2024-07-21-costco-1.jpg", "2024-07-21-costco-2.jpg", "2024-07-21-costco-10.jpg"],
+            vec!["2024-07-21-winco.jpg"],
+            vec!["2024-07-22-costco-1.pdf"],
+        ]);
+    }
+
+    #[test]
+    fn test_scan_stitches_overlapping_photos() {
+        let (_dir, config, ocr) = setup(&[
+            ("2024-07-21-winco-1.png", "WinCo Foods\nONION YLW CO 4093 1.29\nMILK 1234 3.49\n"),
+            ("2024-07-21-winco-2.png", "MILK 1234 3.49\nONION YLW CO 4093 1.30\nTOTAL 6.08\n"),
+        ]);
+        let done_file = config.done_file.clone();
+        let purchases_file = config.purchases_file.clone();
+
+        parse_files(FileItemizer::new(config).unwrap(), &ocr, OutputFormat::Json, ScanOptions::default()).unwrap();
+
+        let purchases = Purchases::init(&purchases_file).unwrap();
+        let prices: Vec<f64> = purchases.iter().map(|p| p.price).collect();
+        assert_eq!(prices, vec![1.29, 3.49, 1.30]);
+        assert_eq!(purchases[0].receipt, purchases[2].receipt);
+        assert_eq!(std::fs::read_to_string(&done_file).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn test_scan_skips_done_images() {
+        let (_dir, config, ocr) = setup(&[("2024-07-21-winco.png", "WinCo Foods\nONION YLW CO 4093 1.29\n")]);
+        let purchases_file = config.purchases_file.clone();
+        let config_again = Config::in_data_dir(config.done_file.parent().unwrap());
+
+        parse_files(FileItemizer::new(config).unwrap(), &ocr, OutputFormat::Json, ScanOptions::default()).unwrap();
+        parse_files(FileItemizer::new(config_again).unwrap(), &ocr, OutputFormat::Json, ScanOptions::default()).unwrap();
+
+        assert_eq!(Purchases::init(&purchases_file).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_scan_unknown_store_is_not_marked_done() {
+        let (_dir, config, ocr) = setup(&[("2024-07-21.png", "CORNER MARKET\nAPPLES 1 2.00\n")]);
+        let done_file = config.done_file.clone();
+
+        parse_files(FileItemizer::new(config).unwrap(), &ocr, OutputFormat::Json, ScanOptions::default()).unwrap();
+
+        assert!(!image_done("2024-07-21.png", &done_file).unwrap());
+        assert!(!done_file.exists());
+    }
+}
@@ -0,0 +1,153 @@
+// © Zach Nielsen 2024
+
+use crate::data::LineParse;
+use crate::output::round_cents;
+
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use serde::Serialize;
+
+use std::path::{Path, PathBuf};
+
+/// How one receipt was read, saved as `<receipt id>.json` to explain a surprising total
+#[derive(Debug, Serialize)]
+pub struct ReceiptReport {
+    pub receipt: String,
+    pub images: Vec<String>,
+    pub date: String,
+    pub store: Option<String>,
+    /// OCR pass that was kept; 0 is the store's normal settings
+    pub attempt: usize,
+    /// Why the receipt couldn't be parsed at all
+    pub error: Option<String>,
+    pub ocr_text: String,
+    pub lines: Vec<LineReport>,
+    pub reconciliation: Option<Reconciliation>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LineReport {
+    /// 1-based line number in `ocr_text`
+    pub number: usize,
+    pub text: String,
+    /// Whether the store's item pattern matched
+    pub matched: bool,
+    pub fields: Option<Fields>,
+    /// Why a matching line wasn't recorded
+    pub skipped: Option<String>,
+    pub rule: Option<RuleReport>,
+    /// Confidence of the code and price, when it was low enough to flag
+    pub confidence: Option<f32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Fields {
+    pub code: u64,
+    pub description: String,
+    pub price: f64,
+}
+
+/// The rule an item resolved to
+#[derive(Debug, Serialize)]
+pub struct RuleReport {
+    pub name: String,
+    pub tags: Vec<String>,
+    /// Added as `UNKNOWN` by this scan
+    pub new: bool,
+}
+
+/// Items recorded against the total printed on the receipt
+#[derive(Debug, Serialize)]
+pub struct Reconciliation {
+    pub items: usize,
+    pub items_total: f64,
+    /// Subtotal, or total when there's no subtotal line
+    pub printed_total: Option<f64>,
+    /// Printed total less the items; usually tax, or a missed or misread line
+    pub difference: Option<f64>,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+impl ReceiptReport {
+    pub fn new(receipt: &str, images: &[String], date: NaiveDate, attempt: usize, ocr_text: &str) -> Self {
+        Self {
+            receipt: receipt.to_owned(),
+            images: images.to_vec(),
+            date: date.to_string(),
+            store: None,
+            attempt,
+            error: None,
+            ocr_text: ocr_text.to_owned(),
+            lines: Vec::new(),
+            reconciliation: None,
+        }
+    }
+
+    /// Sum the recorded items and compare them with the printed total
+    pub fn reconcile(&mut self, printed_total: Option<f64>) {
+        let prices: Vec<f64> = self.lines.iter().filter(|l| l.rule.is_some()).filter_map(|l| l.fields.as_ref().map(|f| f.price)).collect();
+        let items_total = round_cents(prices.iter().sum());
+        self.reconciliation = Some(Reconciliation {
+            items: prices.len(),
+            items_total,
+            printed_total,
+            difference: printed_total.map(|t| round_cents(t - items_total)),
+        });
+    }
+
+    /// Write to `<dir>/<receipt id>.json`, replacing the report from any earlier scan
+    pub fn write(&self, dir: &Path) -> Result<PathBuf> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create receipt report directory: {}", dir.display()))?;
+        let path = dir.join(format!("{}.json", self.receipt));
+        let json = serde_json::to_string_pretty(self).context("Failed to serialize receipt report")?;
+        std::fs::write(&path, json)
+            .with_context(|| format!("Failed to write receipt report: {}", path.display()))?;
+        Ok(path)
+    }
+}
+
+impl LineReport {
+    pub fn new(number: usize, text: &str, parsed: &LineParse) -> Self {
+        let (fields, skipped) = match parsed {
+            LineParse::Item(code, desc, price) => {
+                (Some(Fields { code: *code, description: desc.clone(), price: *price }), None)
+            }
+            LineParse::Skipped(reason) => (None, Some(reason.clone())),
+            LineParse::NoMatch => (None, None),
+        };
+        Self {
+            number,
+            text: text.to_owned(),
+            matched: *parsed != LineParse::NoMatch,
+            fields,
+            skipped,
+            rule: None,
+            confidence: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reconcile() {
+        let date = NaiveDate::from_ymd_opt(2024, 7, 21).unwrap();
+        let mut report = ReceiptReport::new("abc", &["a.jpg".to_owned()], date, 0, "");
+        let rule = || Some(RuleReport { name: "Milk".to_owned(), tags: Vec::new(), new: false });
+        for (i, price) in [5.99, 1.1].into_iter().enumerate() {
+            let mut line = LineReport::new(i + 1, "", &LineParse::Item(1, "MILK".to_owned(), price));
+            line.rule = rule();
+            report.lines.push(line);
+        }
+        report.lines.push(LineReport::new(3, "SUBTOTAL 7.59", &LineParse::NoMatch));
+
+        report.reconcile(Some(7.59));
+        let r = report.reconciliation.as_ref().unwrap();
+        assert_eq!((r.items, r.items_total, r.difference), (2, 7.09, Some(0.5)));
+        assert!(!report.lines[2].matched);
+    }
+}
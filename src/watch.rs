@@ -0,0 +1,137 @@
+// © Zach Nielsen 2024
+
+use crate::data::FileItemizer;
+use crate::export::transactions;
+use crate::images::{self, ImageFilter};
+use crate::ocr::OcrEngine;
+use crate::output::round_cents;
+use crate::scan::{self, ScanOptions};
+
+use anyhow::{Context, Result, bail};
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How often pending files are checked
+const POLL: Duration = Duration::from_millis(500);
+
+/// Files seen changing, held back until their size stops changing so half-synced photos aren't
+/// scanned
+#[derive(Default)]
+pub struct Settler {
+    /// Last size seen, and when it was first seen
+    pending: HashMap<PathBuf, (Option<u64>, Instant)>,
+}
+
+///////////////////////////////////////////////////////////////////////////////////////////////////
+
+impl Settler {
+    pub fn touch(&mut self, path: PathBuf, now: Instant) {
+        self.pending.entry(path)
+            .and_modify(|(_, since)| *since = now)
+            .or_insert((None, now));
+    }
+
+    /// Files whose size hasn't changed for `settle`, in name order. Files that disappear are dropped.
+    pub fn ready(&mut self, now: Instant, settle: Duration) -> Vec<PathBuf> {
+        let mut ready = Vec::new();
+        self.pending.retain(|path, (size, since)| {
+            let Ok(meta) = std::fs::metadata(path) else { return false };
+            if *size != Some(meta.len()) {
+                *size = Some(meta.len());
+                *since = now;
+            } else if meta.len() > 0 && now.duration_since(*since) >= settle {
+                ready.push(path.clone());
+                return false;
+            }
+            true
+        });
+        ready.sort();
+        ready
+    }
+}
+
+/// Scan whatever arrived while nothing was watching, then scan new receipts in the image directory
+/// as they arrive, saving after each one
+pub fn watch(mut itemizer: FileItemizer, ocr: &dyn OcrEngine, options: ScanOptions, settle: Duration) -> Result<()> {
+    let dir = itemizer.config.image_dir.clone();
+    let filter = ImageFilter::new(&itemizer.config)?;
+    let existing = images::image_files(&itemizer.config)?;
+    let scanned = scan::scan_paths(&mut itemizer, existing.paths, ocr, options)?;
+    finish(&itemizer, &scanned);
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)
+        .context("Failed to start watching for new images")?;
+    let mode = if itemizer.config.images.recursive { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+    watcher.watch(&dir, mode)
+        .with_context(|| format!("Failed to watch image directory: {}", dir.display()))?;
+    log::info!("Watching for new receipts in: {}", dir.display());
+
+    let mut settler = Settler::default();
+    loop {
+        match rx.recv_timeout(POLL) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    // Sync tools write to hidden temporary files, then rename them
+                    for path in event.paths.into_iter().filter(|p| p.is_file() && filter.accepts(p)) {
+                        settler.touch(path, Instant::now());
+                    }
+                }
+            }
+            Ok(Err(e)) => log::warn!("error watching image directory: {}", e),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => bail!("Stopped receiving file events for: {}", dir.display()),
+        }
+
+        for parts in scan::group_parts(settler.ready(Instant::now(), settle)) {
+            match scan::scan_paths(&mut itemizer, parts, ocr, options) {
+                Ok(scanned) => finish(&itemizer, &scanned),
+                Err(e) => log::error!("could not scan new receipt: {:?}", e),
+            }
+        }
+    }
+}
+
+/// Print a line per receipt scanned
+fn finish(itemizer: &FileItemizer, scanned: &HashSet<String>) {
+    if scanned.is_empty() {
+        return;
+    }
+    scan::print_flagged(itemizer, scanned);
+    for tx in transactions(itemizer.purchases()).iter().filter(|tx| scanned.contains(&tx.id)) {
+        let total = round_cents(tx.purchases.iter().map(|p| p.price).sum());
+        println!("{} {} {} items {:.2} (receipt {})", tx.date, tx.payee, tx.purchases.len(), total, tx.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settler_waits_for_size_to_settle() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("2024-07-21-costco.jpg");
+        let settle = Duration::from_secs(2);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut settler = Settler::default();
+
+        std::fs::write(&path, b"half").unwrap();
+        settler.touch(path.clone(), at(0));
+        assert!(settler.ready(at(0), settle).is_empty());
+        std::fs::write(&path, b"half and the rest").unwrap();
+        assert!(settler.ready(at(1), settle).is_empty());
+        assert!(settler.ready(at(2), settle).is_empty());
+        assert_eq!(settler.ready(at(3), settle), vec![path.clone()]);
+        assert!(settler.ready(at(4), settle).is_empty());
+
+        settler.touch(dir.path().join("gone.jpg"), at(5));
+        assert!(settler.ready(at(10), settle).is_empty());
+        assert!(settler.pending.is_empty());
+    }
+}